@@ -1,16 +1,26 @@
 // Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
 
 use std::path::PathBuf;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
-use log::info;
+use log::{info, warn};
 
 use actix_files::NamedFile;
+use actix_web::error::BlockingError;
 use actix_web::web;
-use actix_web::{HttpRequest, Result};
+use actix_web::{HttpRequest, HttpResponse, Result};
+use futures::StreamExt;
+use serde::Deserialize;
 
 use super::domain::*;
+use super::events::EventBus;
+use super::impairment::{Impairment, MediaPath, SessionImpairments};
+use super::keyframe;
+use super::metrics::METRICS;
+use super::snapshot::{self, ImageFormat};
+use super::validation::ValidationReport;
 
+#[derive(Default)]
 pub struct AppState {
     peer: Mutex<Option<Peer>>,
 }
@@ -23,57 +33,422 @@ impl AppState {
     }
 }
 
+/// Registers the server's routes; shared by the server binary and tests that run it
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/metrics", web::get().to(metrics))
+        .route("/events", web::get().to(events))
+        .route("/pending_offer", web::get().to(pending_offer))
+        .route("/sdp", web::get().to(sdp))
+        .route("/impairment", web::get().to(impairment))
+        .route("/snapshot", web::get().to(snapshot))
+        .route("/{filename:.*}", web::get().to(index))
+        .route("/request_offer", web::post().to(request_offer))
+        .route("/provide_answer", web::post().to(provide_answer))
+        .route("/ice_restart", web::post().to(ice_restart))
+        .route("/provide_offer", web::post().to(provide_offer))
+        .route("/add_source/{kind}", web::post().to(add_source))
+        .route("/remove_source/{name}", web::post().to(remove_source))
+        .route("/impairment/{path}", web::post().to(set_impairment))
+        .route("/force_keyframe", web::post().to(force_keyframe))
+        .route("/request_keyframe", web::post().to(request_keyframe))
+        .route(
+            "/set_direction/{mline}/{direction}",
+            web::post().to(set_direction),
+        )
+        .route(
+            "/add_ice_candidate/{mline}",
+            web::post().to(add_ice_candidate),
+        );
+}
+
+/// Runs a call that waits on webrtcbin or the pipeline on the blocking thread pool, so the
+/// server's event loop keeps serving other requests meanwhile
+async fn blocking<F, T>(call: F) -> anyhow::Result<T>
+where
+    F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(call).await.map_err(|e| match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => anyhow::anyhow!("The blocking call was canceled"),
+    })
+}
+
+/// Optional query parameters of a trickled ice candidate
+#[derive(Debug, Deserialize)]
+pub struct CandidateQuery {
+    /// The sdpMid of the candidate; takes precedence over the media line index in the path
+    mid: Option<String>,
+}
+
 pub async fn index(req: HttpRequest) -> Result<NamedFile> {
     let path: PathBuf = req.match_info().query("filename").parse().unwrap();
     Ok(NamedFile::open(path)?)
 }
 
-pub async fn request_offer(state: web::Data<AppState>) -> Result<String> {
-    info!("Receiver requested sdp offer");
+pub async fn request_offer(
+    options: web::Query<SessionOptions>,
+    state: web::Data<AppState>,
+) -> Result<String> {
+    info!("Receiver requested sdp offer: {:?}", options);
 
-    let (pipeline, webrtcbin, rx) =
-        create_send_receive_pipeline().expect("Could not create pipeline");
+    let events = Arc::new(EventBus::new());
+    let impairments = Arc::new(SessionImpairments::new());
+    let (pipeline, webrtcbin, rx) = create_send_receive_pipeline(
+        events.clone(),
+        impairments.clone(),
+        &options,
+    )
+    .expect("Could not create pipeline");
 
-    let offer = get_offer(&webrtcbin, rx).expect("Expected to generate offer");
+    let offering = webrtcbin.clone();
+    let offer_options = options.clone();
+    let (offer, rx) =
+        blocking(move || get_offer(&offering, &offer_options, &rx).map(|offer| (offer, rx)))
+            .await
+            .expect("Expected to generate offer");
 
     let p = Peer {
+        session_id: next_session_id(),
         pipeline,
         webrtcbin,
+        events,
+        local_candidates: Arc::new(Mutex::new(rx)),
+        options: options.into_inner(),
+        impairments,
     };
 
     let mut peer = state.peer.lock().unwrap();
-    *peer = Some(p);
+    if let Some(previous) = peer.replace(p) {
+        close_peer(&previous);
+    }
 
     Ok(offer)
 }
 
-pub async fn provide_answer(body: String, state: web::Data<AppState>) -> Result<String> {
+/// Applies the answer to the current offer. An answer that fails validation is rejected
+/// with a 400, and the json validation report as the body
+pub async fn provide_answer(body: String, state: web::Data<AppState>) -> Result<HttpResponse> {
     info!("Received answer for video receiver: \r\n{}", body);
 
-    if let Some(s) = state.peer.lock().unwrap().as_ref() {
-        process_sdp_answer(&s.webrtcbin, body);
+    let peer = state.peer.lock().unwrap().clone();
+    let s = match peer {
+        Some(s) => s,
+        None => return Ok(HttpResponse::NotFound().body("No active session")),
+    };
+
+    let processed = blocking(move || process_sdp_answer(&s.webrtcbin, &s.options, body)).await;
+    match processed {
+        Ok(_) => {
+            METRICS.answer_accepted();
+            Ok(HttpResponse::Ok().body("ok"))
+        }
+        Err(e) => {
+            warn!("Could not process sdp answer: {}", e);
+            METRICS.answer_rejected();
+            match e.downcast_ref::<ValidationReport>() {
+                Some(report) => Ok(HttpResponse::BadRequest().json(report)),
+                None => Ok(HttpResponse::BadRequest().body(e.to_string())),
+            }
+        }
+    }
+}
+
+/// Restarts ice on the current session, returning a new offer that must be answered
+/// through `provide_answer`
+pub async fn ice_restart(state: web::Data<AppState>) -> Result<HttpResponse> {
+    info!("Receiver requested ice restart");
+
+    let peer = state.peer.lock().unwrap().clone();
+    let s = match peer {
+        Some(s) => s,
+        None => return Ok(HttpResponse::NotFound().body("No active session")),
+    };
+
+    let restarted = blocking(move || {
+        let local_candidates = s.local_candidates.lock().unwrap();
+        restart_ice(&s.webrtcbin, &s.options, &local_candidates)
+    })
+    .await;
+    match restarted {
+        Ok(offer) => Ok(HttpResponse::Ok().body(offer)),
+        Err(e) => Ok(HttpResponse::InternalServerError().body(e.to_string())),
+    }
+}
+
+/// Applies a new offer from the browser to the current session, e.g. a browser initiated
+/// ice restart, and returns the answer
+pub async fn provide_offer(body: String, state: web::Data<AppState>) -> Result<HttpResponse> {
+    info!("Received offer for existing session: \r\n{}", body);
+
+    let peer = state.peer.lock().unwrap().clone();
+    let s = match peer {
+        Some(s) => s,
+        None => return Ok(HttpResponse::NotFound().body("No active session")),
+    };
+
+    let answer = blocking(move || {
+        let local_candidates = s.local_candidates.lock().unwrap();
+        process_sdp_offer(&s.webrtcbin, &s.options, body, &local_candidates)
+    })
+    .await;
+    match answer {
+        Ok(answer) => Ok(HttpResponse::Ok().body(answer)),
+        Err(e) => Ok(HttpResponse::BadRequest().body(e.to_string())),
+    }
+}
+
+/// Adds a test source (video, screen or audio) to the current session. Returns the name
+/// of the new source; the resulting offer is fetched from `pending_offer`
+pub async fn add_source(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse> {
+    let kind = req.match_info().get("kind").unwrap_or_default();
+    let source = match TestSource::from_name(kind) {
+        Some(source) => source,
+        None => return Ok(HttpResponse::BadRequest().body(format!("Unknown source {}", kind))),
+    };
+
+    info!("Adding {:?} source", source);
+
+    let peer = state.peer.lock().unwrap().clone();
+    match peer {
+        Some(s) => match add_test_source(&s.pipeline, &s.webrtcbin, &s.options, source) {
+            Ok(name) => Ok(HttpResponse::Ok().body(name)),
+            Err(e) => Ok(HttpResponse::InternalServerError().body(e.to_string())),
+        },
+        None => Ok(HttpResponse::NotFound().body("No active session")),
+    }
+}
+
+/// Removes a source previously added through `add_source`
+pub async fn remove_source(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse> {
+    let name = req.match_info().get("name").unwrap_or_default();
+
+    info!("Removing source {}", name);
+
+    let peer = state.peer.lock().unwrap().clone();
+    match peer {
+        Some(s) => match remove_test_source(&s.pipeline, &s.webrtcbin, name) {
+            Ok(_) => Ok(HttpResponse::Ok().body("ok")),
+            Err(e) => Ok(HttpResponse::BadRequest().body(e.to_string())),
+        },
+        None => Ok(HttpResponse::NotFound().body("No active session")),
     }
+}
+
+/// Changes the direction of the transceiver at the given mline index, e.g.
+/// `/set_direction/0/recvonly`. The resulting offer is fetched from `pending_offer`
+pub async fn set_direction(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse> {
+    let mline = match req.match_info().get("mline").map(|m| m.parse::<u32>()) {
+        Some(Ok(mline)) => mline,
+        _ => return Ok(HttpResponse::BadRequest().body("Invalid media line")),
+    };
+    let name = req.match_info().get("direction").unwrap_or_default();
+    let direction = match MediaDirection::from_name(name) {
+        Some(direction) => direction,
+        None => return Ok(HttpResponse::BadRequest().body(format!("Unknown direction {}", name))),
+    };
+
+    info!("Setting direction of mline {} to {:?}", mline, direction);
+
+    let peer = state.peer.lock().unwrap().clone();
+    match peer {
+        Some(s) => match set_transceiver_direction(&s.webrtcbin, &s.events, mline, direction) {
+            Ok(_) => Ok(HttpResponse::Ok().body("ok")),
+            Err(e) => Ok(HttpResponse::BadRequest().body(e.to_string())),
+        },
+        None => Ok(HttpResponse::NotFound().body("No active session")),
+    }
+}
+
+/// Makes the session's video encoders produce a keyframe, e.g. for a receiver that joins
+pub async fn force_keyframe(state: web::Data<AppState>) -> Result<HttpResponse> {
+    let peer = state.peer.lock().unwrap().clone();
+    let s = match peer {
+        Some(s) => s,
+        None => return Ok(HttpResponse::NotFound().body("No active session")),
+    };
 
-    Ok("ok".to_string())
+    match keyframe::force_key_unit(&s.pipeline) {
+        0 => Ok(HttpResponse::Conflict().body("No video encoders")),
+        n => Ok(HttpResponse::Ok().body(n.to_string())),
+    }
+}
+
+/// Asks the remote party for a keyframe of every incoming video stream with a PLI
+pub async fn request_keyframe(state: web::Data<AppState>) -> Result<HttpResponse> {
+    let peer = state.peer.lock().unwrap().clone();
+    let s = match peer {
+        Some(s) => s,
+        None => return Ok(HttpResponse::NotFound().body("No active session")),
+    };
+
+    match keyframe::request_key_unit(&s.webrtcbin) {
+        0 => Ok(HttpResponse::Conflict().body("No incoming video")),
+        n => Ok(HttpResponse::Ok().body(n.to_string())),
+    }
+}
+
+/// Returns the impairments of the session's send and receive paths as json
+pub async fn impairment(state: web::Data<AppState>) -> Result<HttpResponse> {
+    let peer = state.peer.lock().unwrap().clone();
+    match peer {
+        Some(s) => Ok(HttpResponse::Ok().json(s.impairments.get())),
+        None => Ok(HttpResponse::NotFound().body("No active session")),
+    }
+}
+
+/// Impairs the send or receive path of the session with the loss, delay, jitter, bandwidth
+/// cap and reordering of the query; parameters left out are not impaired
+pub async fn set_impairment(
+    req: HttpRequest,
+    impairment: web::Query<Impairment>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let name = req.match_info().get("path").unwrap_or_default();
+    let path = match MediaPath::from_name(name) {
+        Some(path) => path,
+        None => return Ok(HttpResponse::BadRequest().body(format!("Unknown path {}", name))),
+    };
+
+    if let Err(e) = impairment.validate() {
+        return Ok(HttpResponse::BadRequest().body(e.to_string()));
+    }
+
+    let peer = state.peer.lock().unwrap().clone();
+    match peer {
+        Some(s) => {
+            s.impairments.set(&s.pipeline, path, impairment.into_inner());
+            Ok(HttpResponse::Ok().json(s.impairments.get()))
+        }
+        None => Ok(HttpResponse::NotFound().body("No active session")),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SnapshotQuery {
+    #[serde(default)]
+    format: ImageFormat,
+    /// the webrtcbin pad of the incoming video, e.g. `src_1`
+    stream: Option<String>,
+}
+
+/// Returns the latest decoded frame of the session's incoming video as a png or jpeg
+pub async fn snapshot(
+    query: web::Query<SnapshotQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let peer = state.peer.lock().unwrap().clone();
+    let s = match peer {
+        Some(s) => s,
+        None => return Ok(HttpResponse::NotFound().body("No active session")),
+    };
+
+    // pulling and encoding the frame waits on the pipeline for up to two seconds
+    let format = query.format;
+    let stream = query.stream.clone();
+    let image = blocking(move || match snapshot::latest_frame(&s.pipeline, stream.as_deref()) {
+        Some(frame) => snapshot::encode(&frame, format).map(Some),
+        None => Ok(None),
+    })
+    .await;
+
+    match image {
+        Ok(Some(image)) => Ok(HttpResponse::Ok()
+            .content_type(format.content_type())
+            .body(image)),
+        Ok(None) => Ok(HttpResponse::Conflict().body("No decoded video")),
+        Err(e) => Ok(HttpResponse::InternalServerError().body(e.to_string())),
+    }
+}
+
+/// Returns the local and remote descriptions of the session as json, both raw and broken
+/// down per media line, along with the differences between the offer and the answer
+pub async fn sdp(state: web::Data<AppState>) -> Result<HttpResponse> {
+    let peer = state.peer.lock().unwrap().clone();
+    let s = match peer {
+        Some(s) => s,
+        None => return Ok(HttpResponse::NotFound().body("No active session")),
+    };
+
+    Ok(HttpResponse::Ok().json(inspect_session_descriptions(&s.webrtcbin)))
+}
+
+/// Returns the offer of a pending renegotiation, or 204 if there is none. The answer is
+/// posted to `provide_answer`
+pub async fn pending_offer(state: web::Data<AppState>) -> Result<HttpResponse> {
+    let peer = state.peer.lock().unwrap().clone();
+    let s = match peer {
+        Some(s) => s,
+        None => return Ok(HttpResponse::NotFound().body("No active session")),
+    };
+
+    let pending = blocking(move || {
+        let local_candidates = s.local_candidates.lock().unwrap();
+        get_pending_offer(&s.webrtcbin, &s.options, &local_candidates)
+    })
+    .await;
+    match pending {
+        Ok(Some(offer)) => Ok(HttpResponse::Ok().body(offer)),
+        Ok(None) => Ok(HttpResponse::NoContent().finish()),
+        Err(e) => Ok(HttpResponse::InternalServerError().body(e.to_string())),
+    }
 }
 
 pub async fn add_ice_candidate(
     req: web::HttpRequest,
+    query: web::Query<CandidateQuery>,
     body: String,
     state: web::Data<AppState>,
-) -> Result<String> {
-    let mline = req
-        .match_info()
-        .get("mline")
-        .map(|s| s.parse::<u32>())
-        .expect("Expected mline in the path")
-        .expect("Could not parse mline index");
+) -> Result<HttpResponse> {
+    let mline = match req.match_info().get("mline").map(|m| m.parse::<u32>()) {
+        Some(Ok(mline)) => mline,
+        _ => return Ok(HttpResponse::BadRequest().body("Invalid media line")),
+    };
 
-    info!("Received ice candidate.: {}, {}", mline, body);
+    info!("Received ice candidate.: {}, {:?}, {}", mline, query.mid, body);
 
-    if let Some(s) = state.peer.lock().unwrap().as_ref() {
-        add_remote_candidate(&s.webrtcbin, mline, &body);
+    let peer = state.peer.lock().unwrap().clone();
+    match peer {
+        Some(s) => {
+            let mline = remote_media_line_index(&s.webrtcbin, mline, query.mid.as_deref());
+            match add_remote_candidate(&s.webrtcbin, &s.options, mline, &body) {
+                Ok(_) => Ok(HttpResponse::Ok().body("ok")),
+                Err(e) => Ok(HttpResponse::BadRequest().body(e.to_string())),
+            }
+        }
+        None => Ok(HttpResponse::NotFound().body("No active session")),
+    }
+}
+
+pub async fn metrics(state: web::Data<AppState>) -> Result<HttpResponse> {
+    let peer = state.peer.lock().unwrap().clone();
+
+    if let Some(s) = peer {
+        let webrtcbin = s.webrtcbin.clone();
+        match blocking(move || get_media_stats(&webrtcbin)).await {
+            Ok(stats) => {
+                METRICS.record_session_bytes(s.session_id, stats.bytes_sent, stats.bytes_received)
+            }
+            Err(e) => warn!("Could not get stats for session {}: {}", s.session_id, e),
+        }
     }
 
-    Ok("ok".to_string())
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render()))
+}
+
+/// Streams the state changes of the current session as server-sent events
+pub async fn events(state: web::Data<AppState>) -> Result<HttpResponse> {
+    let events = match state.peer.lock().unwrap().as_ref() {
+        Some(s) => s.events.subscribe(),
+        None => return Ok(HttpResponse::NotFound().body("No active session")),
+    };
+
+    let stream = events.map(|e| Ok::<_, actix_web::Error>(web::Bytes::from(e.to_sse())));
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(stream))
 }
@@ -0,0 +1,52 @@
+// Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
+
+//! Negotiates a session with a running server and fails unless its media arrives.
+//!
+//! `client [--server <url>] [--query <offer query>] [--no-trickle] [--timeout <seconds>]
+//! [--min-buffers <count>]`
+
+use std::time::Duration;
+
+use gstreamer as gst;
+
+use anyhow::{anyhow, Result};
+
+use webrtcbin_playground::client;
+use webrtcbin_playground::client::{CandidateMode, ClientOptions};
+
+#[actix_rt::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+    gst::init()?;
+
+    let options = parse_args(std::env::args().skip(1))?;
+    let received = client::run(&options).await?;
+
+    received
+        .iter()
+        .for_each(|(kind, buffers)| println!("Received {} {} buffers", buffers, kind));
+
+    Ok(())
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<ClientOptions> {
+    let mut options = ClientOptions::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow!("Missing the value of {}", arg))
+        };
+
+        match arg.as_ref() {
+            "--server" => options.server = value()?,
+            "--query" => options.query = value()?,
+            "--no-trickle" => options.candidates = CandidateMode::InSdp,
+            "--timeout" => options.timeout = Duration::from_secs(value()?.parse()?),
+            "--min-buffers" => options.min_buffers = value()?.parse()?,
+            _ => return Err(anyhow!("Unknown argument {}", arg)),
+        }
+    }
+
+    Ok(options)
+}
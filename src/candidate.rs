@@ -0,0 +1,636 @@
+// Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
+
+//! The canonical representation of an ice candidate, used for parsing, filtering and
+//! logging candidates regardless of whether they came from webrtcbin, a trickle message
+//! or an sdp payload.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use webrtc_sdp::address::Address;
+use webrtc_sdp::attribute_type::{
+    SdpAttributeCandidate, SdpAttributeCandidateTcpType, SdpAttributeCandidateTransport,
+    SdpAttributeCandidateType,
+};
+
+use anyhow::{anyhow, Error, Result};
+
+const PREFIX_CANDIDATE: &str = "candidate:";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CandidateType {
+    Host,
+    Srflx,
+    Prflx,
+    Relay,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TcpType {
+    Active,
+    Passive,
+    Simultaneous,
+}
+
+/// The connection or related address of a candidate; browsers hide their host addresses
+/// behind `<uuid>.local` hostnames
+#[derive(Debug, Clone, PartialEq)]
+pub enum CandidateAddress {
+    Ip(IpAddr),
+    Hostname(String),
+}
+
+/// An ice candidate, following the candidate-attribute grammar of RFC 8839, section 5.1:
+///     candidate:<foundation> <component-id> <transport> <priority> <connection-address> <port>
+///         typ <cand-type> [raddr <rel-addr>] [rport <rel-port>] *(<extension-name> <extension-value>)
+///
+/// Parsing accepts the related address and port, as well as the extension attributes, in
+/// any order. Formatting always writes them in the order used by browsers: raddr, rport,
+/// tcptype, generation, ufrag, network-id, network-cost, followed by any other extensions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub foundation: String,
+    pub component: u32,
+    pub transport: Transport,
+    pub priority: u64,
+    pub address: CandidateAddress,
+    pub port: u16,
+    pub candidate_type: CandidateType,
+    pub related_address: Option<CandidateAddress>,
+    pub related_port: Option<u16>,
+    pub tcp_type: Option<TcpType>,
+    pub generation: Option<u32>,
+    pub ufrag: Option<String>,
+    pub network_id: Option<u32>,
+    pub network_cost: Option<u32>,
+    pub extensions: Vec<(String, String)>,
+}
+
+impl FromStr for Candidate {
+    type Err = Error;
+
+    /// Parses a candidate, with or without the leading `a=` and `candidate:`
+    fn from_str(raw: &str) -> Result<Self> {
+        let trimmed = raw.trim();
+        let attribute = trim_prefix(trimmed, "a=");
+        let attribute = trim_prefix(attribute, PREFIX_CANDIDATE);
+        let mut tokens = Tokens::new(attribute);
+
+        let mut c = Candidate {
+            foundation: parse_foundation(tokens.next("foundation")?)?,
+            component: parse_number::<u32>(tokens.next("component-id")?, "component-id")?,
+            transport: tokens.next("transport")?.parse()?,
+            priority: parse_number::<u64>(tokens.next("priority")?, "priority")?,
+            address: parse_address(tokens.next("connection-address")?, "connection-address")?,
+            port: parse_number::<u16>(tokens.next("port")?, "port")?,
+            candidate_type: {
+                tokens.expect("typ")?;
+                tokens.next("cand-type")?.parse()?
+            },
+            related_address: None,
+            related_port: None,
+            tcp_type: None,
+            generation: None,
+            ufrag: None,
+            network_id: None,
+            network_cost: None,
+            extensions: vec![],
+        };
+
+        while let Some(name) = tokens.try_next() {
+            let value = tokens.next(&format!("value of extension '{}'", name))?;
+
+            match name.to_lowercase().as_ref() {
+                "raddr" => c.related_address = Some(parse_address(value, "raddr")?),
+                "rport" => c.related_port = Some(parse_number::<u16>(value, "rport")?),
+                "tcptype" => c.tcp_type = Some(value.parse()?),
+                "generation" => c.generation = Some(parse_number::<u32>(value, "generation")?),
+                "ufrag" => c.ufrag = Some(value.to_string()),
+                "network-id" => c.network_id = Some(parse_number::<u32>(value, "network-id")?),
+                "network-cost" => {
+                    c.network_cost = Some(parse_number::<u32>(value, "network-cost")?)
+                }
+                _ => c.extensions.push((name.to_string(), value.to_string())),
+            }
+        }
+
+        Ok(c)
+    }
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}{} {} {} {} {} {} typ {}",
+            PREFIX_CANDIDATE,
+            self.foundation,
+            self.component,
+            self.transport,
+            self.priority,
+            self.address,
+            self.port,
+            self.candidate_type
+        )?;
+
+        if let Some(raddr) = &self.related_address {
+            write!(f, " raddr {}", raddr)?;
+        }
+        if let Some(rport) = self.related_port {
+            write!(f, " rport {}", rport)?;
+        }
+        if let Some(tcp_type) = self.tcp_type {
+            write!(f, " tcptype {}", tcp_type)?;
+        }
+        if let Some(generation) = self.generation {
+            write!(f, " generation {}", generation)?;
+        }
+        if let Some(ufrag) = &self.ufrag {
+            write!(f, " ufrag {}", ufrag)?;
+        }
+        if let Some(network_id) = self.network_id {
+            write!(f, " network-id {}", network_id)?;
+        }
+        if let Some(network_cost) = self.network_cost {
+            write!(f, " network-cost {}", network_cost)?;
+        }
+        for (name, value) in self.extensions.iter() {
+            write!(f, " {} {}", name, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl From<Candidate> for SdpAttributeCandidate {
+    fn from(c: Candidate) -> Self {
+        let mut attribute = SdpAttributeCandidate::new(
+            c.foundation,
+            c.component,
+            c.transport.into(),
+            c.priority,
+            c.address.into(),
+            u32::from(c.port),
+            c.candidate_type.into(),
+        );
+
+        attribute.raddr = c.related_address.map(Into::into);
+        attribute.rport = c.related_port.map(u32::from);
+        attribute.tcp_type = c.tcp_type.map(Into::into);
+        attribute.generation = c.generation;
+        attribute.ufrag = c.ufrag;
+        attribute.networkcost = c.network_cost;
+
+        // webrtc_sdp has no field for the network id
+        if let Some(network_id) = c.network_id {
+            attribute
+                .unknown_extensions
+                .push(("network-id".to_string(), network_id.to_string()));
+        }
+        attribute.unknown_extensions.extend(c.extensions);
+
+        attribute
+    }
+}
+
+impl TryFrom<&SdpAttributeCandidate> for Candidate {
+    type Error = Error;
+
+    fn try_from(attribute: &SdpAttributeCandidate) -> Result<Self> {
+        let port = |port: u32, field: &str| {
+            u16::try_from(port).map_err(|_| anyhow!("Candidate {} {} is out of range", field, port))
+        };
+
+        let mut network_id = None;
+        let mut extensions = vec![];
+        for (name, value) in attribute.unknown_extensions.iter() {
+            if name.eq_ignore_ascii_case("network-id") {
+                network_id = Some(parse_number::<u32>(value, "network-id")?);
+            } else {
+                extensions.push((name.clone(), value.clone()));
+            }
+        }
+
+        Ok(Candidate {
+            foundation: attribute.foundation.clone(),
+            component: attribute.component,
+            transport: (&attribute.transport).into(),
+            priority: attribute.priority,
+            address: (&attribute.address).into(),
+            port: port(attribute.port, "port")?,
+            candidate_type: (&attribute.c_type).into(),
+            related_address: attribute.raddr.as_ref().map(Into::into),
+            related_port: attribute.rport.map(|p| port(p, "rport")).transpose()?,
+            tcp_type: attribute.tcp_type.as_ref().map(Into::into),
+            generation: attribute.generation,
+            ufrag: attribute.ufrag.clone(),
+            network_id,
+            network_cost: attribute.networkcost,
+            extensions,
+        })
+    }
+}
+
+impl FromStr for Transport {
+    type Err = Error;
+
+    fn from_str(transport: &str) -> Result<Self> {
+        match transport.to_lowercase().as_ref() {
+            "udp" => Ok(Transport::Udp),
+            "tcp" => Ok(Transport::Tcp),
+            _ => Err(anyhow!("Unknown ice transport type '{}'", transport)),
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Transport::Udp => "UDP",
+            Transport::Tcp => "TCP",
+        }
+        .fmt(f)
+    }
+}
+
+impl From<Transport> for SdpAttributeCandidateTransport {
+    fn from(transport: Transport) -> Self {
+        match transport {
+            Transport::Udp => SdpAttributeCandidateTransport::Udp,
+            Transport::Tcp => SdpAttributeCandidateTransport::Tcp,
+        }
+    }
+}
+
+impl From<&SdpAttributeCandidateTransport> for Transport {
+    fn from(transport: &SdpAttributeCandidateTransport) -> Self {
+        match transport {
+            SdpAttributeCandidateTransport::Udp => Transport::Udp,
+            SdpAttributeCandidateTransport::Tcp => Transport::Tcp,
+        }
+    }
+}
+
+impl FromStr for CandidateType {
+    type Err = Error;
+
+    fn from_str(candidate_type: &str) -> Result<Self> {
+        match candidate_type.to_lowercase().as_ref() {
+            "host" => Ok(CandidateType::Host),
+            "srflx" => Ok(CandidateType::Srflx),
+            "prflx" => Ok(CandidateType::Prflx),
+            "relay" => Ok(CandidateType::Relay),
+            _ => Err(anyhow!("Unknown candidate type value '{}'", candidate_type)),
+        }
+    }
+}
+
+impl fmt::Display for CandidateType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CandidateType::Host => "host",
+            CandidateType::Srflx => "srflx",
+            CandidateType::Prflx => "prflx",
+            CandidateType::Relay => "relay",
+        }
+        .fmt(f)
+    }
+}
+
+impl From<CandidateType> for SdpAttributeCandidateType {
+    fn from(candidate_type: CandidateType) -> Self {
+        match candidate_type {
+            CandidateType::Host => SdpAttributeCandidateType::Host,
+            CandidateType::Srflx => SdpAttributeCandidateType::Srflx,
+            CandidateType::Prflx => SdpAttributeCandidateType::Prflx,
+            CandidateType::Relay => SdpAttributeCandidateType::Relay,
+        }
+    }
+}
+
+impl From<&SdpAttributeCandidateType> for CandidateType {
+    fn from(candidate_type: &SdpAttributeCandidateType) -> Self {
+        match candidate_type {
+            SdpAttributeCandidateType::Host => CandidateType::Host,
+            SdpAttributeCandidateType::Srflx => CandidateType::Srflx,
+            SdpAttributeCandidateType::Prflx => CandidateType::Prflx,
+            SdpAttributeCandidateType::Relay => CandidateType::Relay,
+        }
+    }
+}
+
+impl FromStr for TcpType {
+    type Err = Error;
+
+    fn from_str(tcp_type: &str) -> Result<Self> {
+        match tcp_type.to_lowercase().as_ref() {
+            "active" => Ok(TcpType::Active),
+            "passive" => Ok(TcpType::Passive),
+            "so" => Ok(TcpType::Simultaneous),
+            _ => Err(anyhow!("Unknown candidate tcptype value '{}'", tcp_type)),
+        }
+    }
+}
+
+impl fmt::Display for TcpType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TcpType::Active => "active",
+            TcpType::Passive => "passive",
+            TcpType::Simultaneous => "so",
+        }
+        .fmt(f)
+    }
+}
+
+impl From<TcpType> for SdpAttributeCandidateTcpType {
+    fn from(tcp_type: TcpType) -> Self {
+        match tcp_type {
+            TcpType::Active => SdpAttributeCandidateTcpType::Active,
+            TcpType::Passive => SdpAttributeCandidateTcpType::Passive,
+            TcpType::Simultaneous => SdpAttributeCandidateTcpType::Simultaneous,
+        }
+    }
+}
+
+impl From<&SdpAttributeCandidateTcpType> for TcpType {
+    fn from(tcp_type: &SdpAttributeCandidateTcpType) -> Self {
+        match tcp_type {
+            SdpAttributeCandidateTcpType::Active => TcpType::Active,
+            SdpAttributeCandidateTcpType::Passive => TcpType::Passive,
+            SdpAttributeCandidateTcpType::Simultaneous => TcpType::Simultaneous,
+        }
+    }
+}
+
+impl CandidateAddress {
+    /// True for `.local` hostnames, as used by browsers to hide their host addresses
+    pub fn is_mdns(&self) -> bool {
+        match self {
+            CandidateAddress::Hostname(h) => h.ends_with(".local"),
+            CandidateAddress::Ip(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for CandidateAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CandidateAddress::Ip(ip) => ip.fmt(f),
+            CandidateAddress::Hostname(h) => h.fmt(f),
+        }
+    }
+}
+
+impl From<CandidateAddress> for Address {
+    fn from(address: CandidateAddress) -> Self {
+        match address {
+            CandidateAddress::Ip(ip) => Address::Ip(ip),
+            CandidateAddress::Hostname(h) => Address::Fqdn(h),
+        }
+    }
+}
+
+impl From<&Address> for CandidateAddress {
+    fn from(address: &Address) -> Self {
+        match address {
+            Address::Ip(ip) => CandidateAddress::Ip(*ip),
+            Address::Fqdn(h) => CandidateAddress::Hostname(h.to_lowercase()),
+        }
+    }
+}
+
+/// Walks over the whitespace separated tokens of a candidate, keeping track of the
+/// position so that errors can point at the offending token
+struct Tokens<'a> {
+    tokens: Vec<&'a str>,
+    position: usize,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(raw: &'a str) -> Self {
+        Tokens {
+            tokens: raw.split_whitespace().collect(),
+            position: 0,
+        }
+    }
+
+    fn try_next(&mut self) -> Option<&'a str> {
+        let token = self.tokens.get(self.position).copied();
+        if token.is_some() {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn next(&mut self, expected: &str) -> Result<&'a str> {
+        let position = self.position + 1;
+        self.try_next().ok_or_else(|| {
+            anyhow!(
+                "Candidate ended at token {}; expected {}",
+                position,
+                expected
+            )
+        })
+    }
+
+    fn expect(&mut self, literal: &str) -> Result<()> {
+        let token = self.next(literal)?;
+        if token.eq_ignore_ascii_case(literal) {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "Expected '{}' at token {} of candidate, found '{}'",
+                literal,
+                self.position,
+                token
+            ))
+        }
+    }
+}
+
+/// The value without the prefix, if it has it
+fn trim_prefix<'a>(value: &'a str, prefix: &str) -> &'a str {
+    if value.starts_with(prefix) {
+        &value[prefix.len()..]
+    } else {
+        value
+    }
+}
+
+fn parse_foundation(foundation: &str) -> Result<String> {
+    // foundation = 1*32ice-char, ice-char = ALPHA / DIGIT / "+" / "/"
+    let valid = foundation.len() <= 32
+        && foundation
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/');
+
+    if valid {
+        Ok(foundation.to_string())
+    } else {
+        Err(anyhow!("Invalid candidate foundation '{}'", foundation))
+    }
+}
+
+fn parse_number<T: FromStr>(value: &str, field: &str) -> Result<T> {
+    value
+        .parse::<T>()
+        .map_err(|_| anyhow!("Invalid candidate {} '{}'", field, value))
+}
+
+fn parse_address(value: &str, field: &str) -> Result<CandidateAddress> {
+    if let Ok(ip) = IpAddr::from_str(value) {
+        return Ok(CandidateAddress::Ip(ip));
+    }
+
+    // hostnames, most commonly mdns names; see RFC 1123 section 2.1
+    let valid = !value.is_empty()
+        && value.len() <= 253
+        && value.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+
+    if valid {
+        Ok(CandidateAddress::Hostname(value.to_lowercase()))
+    } else {
+        Err(anyhow!("Invalid candidate {} '{}'", field, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Candidates as emitted by the various ice implementations we interoperate with
+    const CORPUS: &[&str] = &[
+        // chrome
+        "candidate:3719404024 1 udp 2122260223 192.168.0.91 55827 typ host generation 0 network-id 1 network-cost 10",
+        "candidate:2999745851 1 udp 2122194687 10.0.75.1 62431 typ host generation 0 network-id 2",
+        "candidate:1242546700 1 udp 2122262783 2001:db8::1c2c:9b4d:fe5b:7a21 55828 typ host generation 0 network-id 3 network-cost 10",
+        "candidate:842163049 1 udp 1686052607 203.0.113.17 55827 typ srflx raddr 192.168.0.91 rport 55827 generation 0 network-id 1 network-cost 10",
+        "candidate:2540970392 1 tcp 1518280447 192.168.0.91 9 typ host tcptype active generation 0 network-id 1 network-cost 10",
+        "candidate:1610451593 1 udp 2113937151 0f0ed1c5-7ec6-4a0d-a3a4-4d8e1f4c6b1a.local 52941 typ host generation 0 ufrag 7Ksc network-cost 999",
+        "candidate:4234997325 1 udp 25108223 198.51.100.4 60104 typ relay raddr 203.0.113.17 rport 55827 generation 0 ufrag 7Ksc network-id 1 network-cost 10",
+        // firefox
+        "candidate:0 1 UDP 2122252543 192.168.1.12 50374 typ host",
+        "candidate:1 1 UDP 2122187007 9f1c8a62-1b8e-4f38-9f5f-d5a4f7c3a0e2.local 59311 typ host",
+        "candidate:2 1 TCP 2105524479 192.168.1.12 9 typ host tcptype active",
+        "candidate:3 1 UDP 1686052863 203.0.113.17 50374 typ srflx raddr 192.168.1.12 rport 50374",
+        "candidate:4 1 UDP 92217343 198.51.100.4 49153 typ relay raddr 198.51.100.4 rport 49153",
+        // safari
+        "candidate:2791134329 1 udp 2113937151 5e9a1f34-8d12-4cbe-9a0f-2b3c4d5e6f70.local 62052 typ host generation 0 network-cost 999",
+        "candidate:842163049 1 udp 1677732095 203.0.113.17 62052 typ srflx raddr 0.0.0.0 rport 0 generation 0 network-cost 999",
+        // libnice, as emitted by webrtcbin
+        "candidate:1 1 UDP 2015363327 192.168.1.12 41537 typ host",
+        "candidate:5 1 TCP 1015021823 192.168.1.12 9 typ host tcptype active",
+        "candidate:6 1 TCP 1010827519 192.168.1.12 43557 typ host tcptype passive",
+        "candidate:13 1 UDP 1679819007 203.0.113.17 41537 typ srflx raddr 192.168.1.12 rport 41537",
+        "candidate:9 1 TCP 1007614207 fe80::a00:27ff:fe4e:66a1 34075 typ host tcptype so",
+    ];
+
+    #[test]
+    fn corpus_round_trips_through_strings() {
+        for raw in CORPUS {
+            let parsed = Candidate::from_str(raw).expect(raw);
+            let formatted = parsed.to_string();
+
+            // only the case of the transport is normalized
+            assert!(
+                formatted.eq_ignore_ascii_case(raw),
+                "{} != {}",
+                formatted,
+                raw
+            );
+            assert_eq!(Candidate::from_str(&formatted).unwrap(), parsed);
+        }
+    }
+
+    #[test]
+    fn corpus_round_trips_through_webrtc_sdp() {
+        for raw in CORPUS {
+            let parsed = Candidate::from_str(raw).expect(raw);
+            let attribute = SdpAttributeCandidate::from(parsed.clone());
+
+            assert_eq!(Candidate::try_from(&attribute).unwrap(), parsed);
+        }
+    }
+
+    #[test]
+    fn corpus_matches_webrtc_sdp_parser() {
+        for raw in CORPUS {
+            let parsed = Candidate::from_str(raw).expect(raw);
+            let line = format!("a={}", raw);
+
+            match webrtc_sdp::attribute_type::parse_attribute(&line[2..]) {
+                Ok(webrtc_sdp::SdpType::Attribute(
+                    webrtc_sdp::attribute_type::SdpAttribute::Candidate(attribute),
+                )) => assert_eq!(Candidate::try_from(&attribute).unwrap(), parsed),
+                _ => panic!("webrtc_sdp could not parse {}", raw),
+            }
+        }
+    }
+
+    #[test]
+    fn extensions_are_accepted_in_any_order() {
+        let c = Candidate::from_str(
+            "candidate:1 1 tcp 1 10.0.0.1 9 typ srflx network-cost 5 tcptype passive rport 7 foo bar raddr 10.0.0.2",
+        )
+        .unwrap();
+
+        assert_eq!(
+            c.related_address,
+            Some(CandidateAddress::Ip("10.0.0.2".parse().unwrap()))
+        );
+        assert_eq!(c.related_port, Some(7));
+        assert_eq!(c.tcp_type, Some(TcpType::Passive));
+        assert_eq!(c.network_cost, Some(5));
+        assert_eq!(c.extensions, vec![("foo".to_string(), "bar".to_string())]);
+    }
+
+    #[test]
+    fn mdns_hostnames_are_parsed_as_hostnames() {
+        let c = Candidate::from_str(CORPUS[5]).unwrap();
+
+        assert!(c.address.is_mdns());
+        assert_eq!(
+            Address::from(c.address),
+            Address::Fqdn("0f0ed1c5-7ec6-4a0d-a3a4-4d8e1f4c6b1a.local".to_string())
+        );
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_token() {
+        let error = |raw: &str| Candidate::from_str(raw).unwrap_err().to_string();
+
+        assert_eq!(
+            error("candidate:1 1 udp 1 10.0.0.1 5 typ host generation"),
+            "Candidate ended at token 10; expected value of extension 'generation'"
+        );
+        assert_eq!(
+            error("candidate:1 1 udp 1 10.0.0.1 5 type host"),
+            "Expected 'typ' at token 7 of candidate, found 'type'"
+        );
+        assert_eq!(
+            error("candidate:1 1 udp 1 10.0.0.1 70000 typ host"),
+            "Invalid candidate port '70000'"
+        );
+        assert_eq!(
+            error("candidate:1 1 sctp 1 10.0.0.1 5 typ host"),
+            "Unknown ice transport type 'sctp'"
+        );
+        assert_eq!(
+            error("candidate:1 1 tcp 1 10.0.0.1 5 typ host tcptype both"),
+            "Unknown candidate tcptype value 'both'"
+        );
+    }
+}
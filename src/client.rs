@@ -0,0 +1,324 @@
+// Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
+
+//! A headless signaling client, whose own webrtcbin answers the server's offer, so that a
+//! whole negotiation can run without a browser: request an offer, answer it, send the
+//! local candidates, and count the buffers received for the media the server sends.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use gst::prelude::*;
+use gstreamer as gst;
+
+use actix_rt::time::delay_for;
+use actix_web::client::Client;
+use actix_web::web;
+use anyhow::{anyhow, Result};
+
+use super::domain::*;
+use super::inspect;
+use super::validation;
+
+const DEFAULT_SERVER: &str = "http://127.0.0.1:8080";
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_MIN_BUFFERS: u64 = 50;
+const CANDIDATE_TIMEOUT_MS: u64 = 2000;
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// How the local candidates reach the server
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CandidateMode {
+    /// posted to `/add_ice_candidate/{mline}` as they are gathered
+    Trickle,
+    /// included in the answer
+    InSdp,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    pub server: String,
+    /// the query of the offer request, e.g. `direction=sendonly&bundle_policy=balanced`
+    pub query: String,
+    pub candidates: CandidateMode,
+    /// how long to wait for media once the answer has been accepted
+    pub timeout: Duration,
+    /// the buffers needed for every kind of media the server sends
+    pub min_buffers: u64,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            server: DEFAULT_SERVER.to_string(),
+            query: String::new(),
+            candidates: CandidateMode::Trickle,
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            min_buffers: DEFAULT_MIN_BUFFERS,
+        }
+    }
+}
+
+/// Rtp buffers received, per kind of media
+#[derive(Debug, Default)]
+pub struct BufferCounts {
+    video: AtomicU64,
+    audio: AtomicU64,
+}
+
+impl BufferCounts {
+    fn count(&self, kind: &str) {
+        match kind {
+            "video" => self.video.fetch_add(1, Ordering::Relaxed),
+            "audio" => self.audio.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+    }
+
+    pub fn get(&self, kind: &str) -> u64 {
+        match kind {
+            "video" => self.video.load(Ordering::Relaxed),
+            "audio" => self.audio.load(Ordering::Relaxed),
+            _ => 0,
+        }
+    }
+}
+
+/// A webrtcbin that answers offers and counts the buffers it receives; it has no sources
+pub struct HeadlessPeer {
+    pub pipeline: gst::Pipeline,
+    pub webrtcbin: gst::Element,
+    pub local_candidates: Arc<Mutex<Receiver<IceCandidate>>>,
+    pub buffers: Arc<BufferCounts>,
+}
+
+impl HeadlessPeer {
+    pub fn new() -> Result<Self> {
+        let pipeline = gst::Pipeline::new(None);
+        let webrtcbin = gst::ElementFactory::make("webrtcbin", Some("webrtcbin"))?;
+        pipeline.add(&webrtcbin)?;
+
+        let (ice_tx, ice_rx) = mpsc::channel();
+        listen_for_local_candidates(&webrtcbin, ice_tx, false);
+
+        let buffers = Arc::new(BufferCounts::default());
+        let counted = buffers.clone();
+        let pad_added_pipeline = pipeline.clone();
+        webrtcbin.connect_pad_added(move |_webrtc, pad| {
+            if let Err(e) = count_incoming_stream(&pad_added_pipeline, pad, counted.clone()) {
+                warn!("Could not receive incoming stream: {}", e);
+            }
+        });
+
+        pipeline
+            .set_state(gst::State::Playing)
+            .map_err(|e| anyhow!("Could not start the client pipeline: {:?}", e))?;
+
+        Ok(HeadlessPeer {
+            pipeline,
+            webrtcbin,
+            local_candidates: Arc::new(Mutex::new(ice_rx)),
+            buffers,
+        })
+    }
+
+    /// Applies an offer and returns the answer, which only includes the local candidates
+    /// with `CandidateMode::InSdp`. Blocks until webrtcbin has created the answer.
+    pub fn answer(&self, offer: String, mode: CandidateMode) -> Result<String> {
+        let options = SessionOptions::default();
+        let local_candidates = self.local_candidates.lock().unwrap();
+
+        match mode {
+            CandidateMode::Trickle => {
+                answer_sdp_offer(&self.webrtcbin, &options, offer, &local_candidates)
+            }
+            CandidateMode::InSdp => {
+                process_sdp_offer(&self.webrtcbin, &options, offer, &local_candidates)
+            }
+        }
+    }
+}
+
+impl Drop for HeadlessPeer {
+    fn drop(&mut self) {
+        if let Err(e) = self.pipeline.set_state(gst::State::Null) {
+            warn!("Could not stop the client pipeline: {:?}", e);
+        }
+    }
+}
+
+/// Negotiates a session with the server and waits for its media. Returns the buffers
+/// received for every kind of media the server sends, or an error if any of them did not
+/// reach `min_buffers` in time.
+pub async fn run(options: &ClientOptions) -> Result<Vec<(String, u64)>> {
+    let client = Client::default();
+    let peer = Arc::new(HeadlessPeer::new()?);
+
+    let offer_url = format!("{}/request_offer?{}", options.server, options.query);
+    let offer = post(&client, &offer_url, String::new()).await?;
+    let expected = sent_media_kinds(&offer)?;
+    info!("Received offer, expecting {:?} media", expected);
+
+    // webrtcbin's promises and the candidate channel block, so they are waited for on the
+    // blocking thread pool rather than on the server's event loop
+    let answering = peer.clone();
+    let mode = options.candidates;
+    let answer = web::block(move || answering.answer(offer, mode))
+        .await
+        .map_err(|e| anyhow!("Could not answer the offer: {}", e))?;
+    post(
+        &client,
+        &format!("{}/provide_answer", options.server),
+        answer,
+    )
+    .await?;
+
+    if options.candidates == CandidateMode::Trickle {
+        trickle_candidates(&client, &options.server, &peer.local_candidates).await?;
+    }
+
+    wait_for_media(&peer.buffers, &expected, options).await
+}
+
+async fn post(client: &Client, url: &str, body: String) -> Result<String> {
+    let mut response = client
+        .post(url)
+        .send_body(body)
+        .await
+        .map_err(|e| anyhow!("Request to {} failed: {}", url, e))?;
+    let body = response
+        .body()
+        .await
+        .map_err(|e| anyhow!("Could not read the response of {}: {}", url, e))?;
+    let text = String::from_utf8_lossy(&body).to_string();
+
+    if !response.status().is_success() {
+        return Err(anyhow!("{} returned {}: {}", url, response.status(), text));
+    }
+
+    Ok(text)
+}
+
+/// Posts the local candidates as they are gathered, up to the end of candidates of every
+/// media line
+async fn trickle_candidates(
+    client: &Client,
+    server: &str,
+    local_candidates: &Arc<Mutex<Receiver<IceCandidate>>>,
+) -> Result<()> {
+    loop {
+        let receiver = local_candidates.clone();
+        let next = web::block(move || next_candidates(&receiver.lock().unwrap()));
+        let candidates = match next.await {
+            Ok(candidates) => candidates,
+            // gathering stalled; the candidates so far may still be enough
+            Err(_) => break,
+        };
+        let end = candidates.iter().any(IceCandidate::is_end_of_candidates);
+
+        for candidate in candidates {
+            let url = format!(
+                "{}/add_ice_candidate/{}",
+                server,
+                candidate.get_media_line_index()
+            );
+            post(client, &url, candidate.get_candidate_str()).await?;
+        }
+
+        if end {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Waits for the next local candidate. The end-of-candidates markers of all media lines are
+/// sent together, so they are returned together.
+fn next_candidates(
+    local_candidates: &Receiver<IceCandidate>,
+) -> std::result::Result<Vec<IceCandidate>, RecvTimeoutError> {
+    let candidate = local_candidates.recv_timeout(Duration::from_millis(CANDIDATE_TIMEOUT_MS))?;
+    let mut candidates = vec![candidate];
+    if candidates[0].is_end_of_candidates() {
+        candidates.extend(take_end_of_candidates(local_candidates));
+    }
+
+    Ok(candidates)
+}
+
+/// The kinds of media the offer sends, i.e. of its active sendrecv and sendonly media lines
+fn sent_media_kinds(offer: &str) -> Result<Vec<String>> {
+    let session = validation::parse(offer).map_err(anyhow::Error::new)?;
+
+    let mut kinds = inspect::summarize(&session)
+        .media
+        .into_iter()
+        .filter(|m| m.port != 0 || m.bundle_only)
+        .filter(|m| m.direction == "sendrecv" || m.direction == "sendonly")
+        .map(|m| m.kind)
+        .collect::<Vec<_>>();
+    kinds.sort();
+    kinds.dedup();
+
+    Ok(kinds)
+}
+
+async fn wait_for_media(
+    buffers: &BufferCounts,
+    expected: &[String],
+    options: &ClientOptions,
+) -> Result<Vec<(String, u64)>> {
+    let started = Instant::now();
+
+    loop {
+        let received = expected
+            .iter()
+            .map(|kind| (kind.clone(), buffers.get(kind)))
+            .collect::<Vec<_>>();
+
+        if received.iter().all(|(_, n)| *n >= options.min_buffers) {
+            return Ok(received);
+        }
+
+        if started.elapsed() > options.timeout {
+            return Err(anyhow!(
+                "Expected {} buffers of each media within {:?}, but received {:?}",
+                options.min_buffers,
+                options.timeout,
+                received
+            ));
+        }
+
+        delay_for(Duration::from_millis(POLL_INTERVAL_MS)).await;
+    }
+}
+
+/// Counts the buffers of an incoming stream, then discards it
+fn count_incoming_stream(
+    pipeline: &gst::Pipeline,
+    pad: &gst::Pad,
+    buffers: Arc<BufferCounts>,
+) -> Result<()> {
+    if pad.get_direction() != gst::PadDirection::Src {
+        return Ok(());
+    }
+
+    // the caps of the rtp stream are only known once it flows
+    pad.add_probe(gst::PadProbeType::BUFFER, move |pad, _info| {
+        if let Some(kind) = media_kind(pad) {
+            buffers.count(&kind);
+        }
+        gst::PadProbeReturn::Ok
+    });
+
+    discard_incoming_stream(pipeline, pad)
+}
+
+fn media_kind(pad: &gst::Pad) -> Option<String> {
+    let caps = pad.get_current_caps()?;
+    let structure = caps.get_structure(0)?;
+    structure.get::<String>("media").ok().flatten()
+}
@@ -0,0 +1,322 @@
+// Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
+
+//! Congestion control of a session's video. Transport-wide cc sequence numbers are
+//! negotiated with an extmap, and the video encoders' bitrate and resolution follow an
+//! estimate of the available bandwidth. Gstreamer's `rtpgccbwe` provides the estimate where
+//! webrtcbin supports aux senders; otherwise a loss-based controller does, driven by the
+//! receiver reports in webrtcbin's stats.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use gst::prelude::*;
+use gstreamer as gst;
+use gstreamer_webrtc as gst_webrtc;
+
+use anyhow::{anyhow, Result};
+
+use super::events::EventBus;
+use super::gstlib::update_property;
+
+/// The header extension carrying transport-wide cc sequence numbers
+pub const TWCC_URI: &str =
+    "http://www.ietf.org/id/draft-holmer-rmcat-transport-wide-cc-extensions-01";
+pub(crate) const TWCC_EXTMAP_ID: u32 = 1;
+
+pub(crate) const ENCODER_PREFIX: &str = "video-encoder";
+const SCALE_PREFIX: &str = "video-scale";
+pub(crate) const RTP_CAPS_PREFIX: &str = "rtp-caps";
+
+const MIN_BITRATE: u32 = 100_000;
+const MAX_BITRATE: u32 = 2_500_000;
+// vp8enc's default
+const START_BITRATE: u32 = 256_000;
+// left to the audio when the estimate covers the whole session
+const AUDIO_BITRATE: u32 = 64_000;
+const STATS_INTERVAL_MS: u64 = 1000;
+
+/// The resolutions of the video, by the minimum bitrate they need
+const RESOLUTIONS: [(u32, u32, u32); 4] = [
+    (1_500_000, 1280, 720),
+    (800_000, 960, 540),
+    (350_000, 640, 360),
+    (0, 320, 180),
+];
+
+/// Loss-based rate control, as in the google congestion control draft: back off when more
+/// than a tenth of the packets are lost, and probe upwards while hardly any are
+#[derive(Debug, Clone)]
+pub struct LossBasedController {
+    bitrate: u32,
+}
+
+impl LossBasedController {
+    pub fn new(bitrate: u32) -> Self {
+        LossBasedController {
+            bitrate: clamp_bitrate(bitrate),
+        }
+    }
+
+    /// Updates the bitrate with the fraction of packets lost since the last report
+    pub fn update(&mut self, fraction_lost: f64) -> u32 {
+        let bitrate = f64::from(self.bitrate);
+        let next = if fraction_lost > 0.1 {
+            bitrate * (1.0 - 0.5 * fraction_lost)
+        } else if fraction_lost < 0.02 {
+            bitrate * 1.08
+        } else {
+            bitrate
+        };
+
+        self.bitrate = clamp_bitrate(next.round() as u32);
+        self.bitrate
+    }
+}
+
+fn clamp_bitrate(bitrate: u32) -> u32 {
+    bitrate.max(MIN_BITRATE).min(MAX_BITRATE)
+}
+
+/// The largest resolution the bitrate supports, as width and height
+pub fn resolution_for(bitrate: u32) -> (u32, u32) {
+    RESOLUTIONS
+        .iter()
+        .find(|(min, _, _)| bitrate >= *min)
+        .map(|(_, width, height)| (*width, *height))
+        .unwrap_or((320, 180))
+}
+
+/// Adds the transport-wide cc extmap to rtp caps. Webrtcbin offers the extmaps of its
+/// caps, and rtpbin writes the sequence numbers of the extmap it finds.
+pub fn add_twcc_extmap(caps: &mut gst::Caps) {
+    let field = format!("extmap-{}", TWCC_EXTMAP_ID);
+    caps.get_mut()
+        .expect("Caps are not writable")
+        .iter_mut()
+        .for_each(|s| s.set(&field, &TWCC_URI));
+}
+
+/// Negotiates transport-wide cc on the rtp streams of the pipeline that do not yet, e.g.
+/// after adding a source
+pub fn negotiate_twcc(pipeline: &gst::Pipeline) {
+    pipeline
+        .iterate_recurse()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.get_name().starts_with(RTP_CAPS_PREFIX))
+        .for_each(|e| {
+            // keep the fields the capsfilter already adds
+            let mut caps = e
+                .get_property("caps")
+                .ok()
+                .and_then(|v| v.get::<gst::Caps>().ok().flatten())
+                .filter(|c| !c.is_any() && !c.is_empty())
+                .unwrap_or_else(|| gst::Caps::new_simple("application/x-rtp", &[]));
+            add_twcc_extmap(&mut caps);
+
+            if let Err(err) = update_property(&e, "caps", caps) {
+                warn!("Could not negotiate twcc on {}: {}", e.get_name(), err);
+            }
+        });
+}
+
+/// Enables congestion control on a new session, before it is negotiated: negotiates
+/// transport-wide cc and adapts the video encoders to the estimated bandwidth from then on.
+/// The target bitrate is published as the `target-bitrate` event whenever it changes.
+pub fn enable(pipeline: &gst::Pipeline, webrtcbin: &gst::Element, events: Arc<EventBus>) {
+    negotiate_twcc(pipeline);
+    adapt(pipeline, &events, START_BITRATE);
+
+    if gst::ElementFactory::find("rtpgccbwe").is_some() {
+        match estimate_with_gcc(pipeline, webrtcbin, events.clone()) {
+            Ok(_) => return,
+            Err(e) => info!("Not using rtpgccbwe: {}", e),
+        }
+    }
+
+    info!("Adapting the video to the loss in receiver reports");
+    if let Err(e) = control_with_loss(pipeline, webrtcbin, events) {
+        warn!("Could not start the bitrate controller: {}", e);
+    }
+}
+
+/// Hands webrtcbin a `rtpgccbwe` as the aux sender of its transports, and follows the
+/// bandwidth it estimates from the transport-wide cc feedback
+fn estimate_with_gcc(
+    pipeline: &gst::Pipeline,
+    webrtcbin: &gst::Element,
+    events: Arc<EventBus>,
+) -> Result<()> {
+    let pipeline = pipeline.downgrade();
+
+    webrtcbin
+        .connect("request-aux-sender", false, move |_values| {
+            let estimator = match gst::ElementFactory::make("rtpgccbwe", None) {
+                Ok(e) => e,
+                Err(e) => {
+                    warn!("Could not create rtpgccbwe: {}", e);
+                    return None;
+                }
+            };
+
+            let pipeline = pipeline.clone();
+            let events = events.clone();
+            estimator.connect_notify(Some("estimated-bitrate"), move |estimator, _| {
+                let estimate = estimator
+                    .get_property("estimated-bitrate")
+                    .ok()
+                    .and_then(|v| v.get_some::<u32>().ok());
+
+                if let (Some(estimate), Some(pipeline)) = (estimate, pipeline.upgrade()) {
+                    let bitrate = clamp_bitrate(estimate.saturating_sub(AUDIO_BITRATE));
+                    adapt(&pipeline, &events, bitrate);
+                }
+            });
+
+            Some(estimator.to_value())
+        })
+        .map_err(|e| anyhow!("Webrtcbin has no aux senders: {}", e))?;
+
+    Ok(())
+}
+
+/// Polls webrtcbin's stats for the loss reported by the remote party, until the session
+/// is closed
+fn control_with_loss(
+    pipeline: &gst::Pipeline,
+    webrtcbin: &gst::Element,
+    events: Arc<EventBus>,
+) -> Result<()> {
+    let pipeline = pipeline.downgrade();
+    let webrtcbin = webrtcbin.downgrade();
+    let interval = Duration::from_millis(STATS_INTERVAL_MS);
+
+    thread::Builder::new()
+        .name("bitrate-controller".to_string())
+        .spawn(move || {
+            let mut controller = LossBasedController::new(START_BITRATE);
+            loop {
+                thread::sleep(interval);
+
+                let (pipeline, webrtcbin) = match (pipeline.upgrade(), webrtcbin.upgrade()) {
+                    (Some(p), Some(w)) => (p, w),
+                    _ => return,
+                };
+                match pipeline.get_state(gst::ClockTime::from_seconds(0)).1 {
+                    gst::State::Null => return,
+                    gst::State::Playing => (),
+                    _ => continue,
+                }
+
+                // there is nothing to go by until the remote party reports
+                if let Some(lost) = fraction_lost(&webrtcbin) {
+                    adapt(&pipeline, &events, controller.update(lost));
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+/// The largest fraction of packets lost in the remote party's latest receiver reports
+fn fraction_lost(webrtcbin: &gst::Element) -> Option<f64> {
+    let promise = gst::Promise::new();
+    webrtcbin
+        .emit("get-stats", &[&None::<gst::Pad>, &promise])
+        .ok()?;
+    promise.wait();
+
+    promise
+        .get_reply()?
+        .iter()
+        .filter_map(|(_, value)| value.get::<gst::Structure>().ok().flatten())
+        .filter(|s| {
+            matches!(
+                s.get_some::<gst_webrtc::WebRTCStatsType>("type"),
+                Ok(gst_webrtc::WebRTCStatsType::RemoteInboundRtp)
+            )
+        })
+        .filter_map(|s| s.get_some::<f64>("fraction-lost").ok())
+        .fold(None, |max, lost| Some(lost.max(max.unwrap_or(0.0))))
+}
+
+/// Sets the target bitrate of the pipeline's video encoders, and scales their video to the
+/// resolution that bitrate supports
+fn adapt(pipeline: &gst::Pipeline, events: &EventBus, bitrate: u32) {
+    let (width, height) = resolution_for(bitrate);
+    let caps = gst::Caps::new_simple(
+        "video/x-raw",
+        &[("width", &(width as i32)), ("height", &(height as i32))],
+    );
+
+    let mut changed = false;
+    pipeline
+        .iterate_recurse()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .for_each(|e| {
+            let name = e.get_name();
+            let result = if name.starts_with(ENCODER_PREFIX) {
+                update_property(&e, "target-bitrate", bitrate as i32)
+            } else if name.starts_with(SCALE_PREFIX) {
+                update_property(&e, "caps", caps.clone())
+            } else {
+                Ok(false)
+            };
+
+            match result {
+                Ok(updated) => changed |= updated,
+                Err(err) => warn!("Could not adapt {}: {}", name, err),
+            }
+        });
+
+    if changed {
+        debug!("Video adapted to {} bps at {}x{}", bitrate, width, height);
+        events.publish("target-bitrate", &bitrate.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_on_heavy_loss() {
+        let mut controller = LossBasedController::new(1_000_000);
+
+        assert_eq!(controller.update(0.2), 900_000);
+        assert_eq!(controller.update(0.5), 675_000);
+    }
+
+    #[test]
+    fn holds_on_moderate_loss() {
+        let mut controller = LossBasedController::new(1_000_000);
+
+        assert_eq!(controller.update(0.05), 1_000_000);
+    }
+
+    #[test]
+    fn probes_without_loss() {
+        let mut controller = LossBasedController::new(1_000_000);
+
+        assert_eq!(controller.update(0.0), 1_080_000);
+    }
+
+    #[test]
+    fn stays_within_bounds() {
+        let mut controller = LossBasedController::new(MIN_BITRATE);
+        assert_eq!(controller.update(1.0), MIN_BITRATE);
+
+        let mut controller = LossBasedController::new(MAX_BITRATE);
+        assert_eq!(controller.update(0.0), MAX_BITRATE);
+    }
+
+    #[test]
+    fn lowers_resolution_with_bitrate() {
+        assert_eq!(resolution_for(2_000_000), (1280, 720));
+        assert_eq!(resolution_for(800_000), (960, 540));
+        assert_eq!(resolution_for(500_000), (640, 360));
+        assert_eq!(resolution_for(MIN_BITRATE), (320, 180));
+    }
+}
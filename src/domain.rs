@@ -1,10 +1,12 @@
 // Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
 
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use gst::prelude::*;
 use gstreamer as gst;
@@ -13,16 +15,242 @@ use gstreamer_sdp as gst_sdp;
 use gstreamer_webrtc as gst_webrtc;
 
 use anyhow::{anyhow, Result};
-
+use serde::Deserialize;
+
+use super::candidate::{Candidate, CandidateAddress, CandidateType};
+use super::congestion;
+use super::events::EventBus;
+use super::extmap;
+use super::extmap::HeaderExtension;
+use super::inspect;
+use super::inspect::{DescriptionReport, SessionDescriptions};
 use super::gstlib::*;
+use super::impairment::{self, SessionImpairments};
+use super::keyframe;
+use super::mdns;
+use super::metrics::METRICS;
 use super::moz_ice;
+use super::munging;
+use super::munging::{SdpTransform, Stage};
+use super::sdp_text;
+use super::simulcast;
+use super::snapshot;
+use super::voice;
+use super::validation;
 
 type StdResult<L, R> = std::result::Result<L, R>;
 
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+static NEXT_SOURCE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Direction of the session's media, from the media server's point of view
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaDirection {
+    SendRecv,
+    SendOnly,
+    RecvOnly,
+    Inactive,
+}
+
+impl Default for MediaDirection {
+    fn default() -> Self {
+        MediaDirection::SendRecv
+    }
+}
+
+impl MediaDirection {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sendrecv" => Some(MediaDirection::SendRecv),
+            "sendonly" => Some(MediaDirection::SendOnly),
+            "recvonly" => Some(MediaDirection::RecvOnly),
+            "inactive" => Some(MediaDirection::Inactive),
+            _ => None,
+        }
+    }
+
+    fn to_transceiver_direction(self) -> gst_webrtc::WebRTCRTPTransceiverDirection {
+        match self {
+            MediaDirection::SendRecv => gst_webrtc::WebRTCRTPTransceiverDirection::Sendrecv,
+            MediaDirection::SendOnly => gst_webrtc::WebRTCRTPTransceiverDirection::Sendonly,
+            MediaDirection::RecvOnly => gst_webrtc::WebRTCRTPTransceiverDirection::Recvonly,
+            MediaDirection::Inactive => gst_webrtc::WebRTCRTPTransceiverDirection::Inactive,
+        }
+    }
+}
+
+/// Webrtcbin's bundle policy; see the `bundle-policy` property and RFC 8843
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BundlePolicy {
+    None,
+    Balanced,
+    MaxCompat,
+    MaxBundle,
+}
+
+impl Default for BundlePolicy {
+    fn default() -> Self {
+        BundlePolicy::MaxBundle
+    }
+}
+
+impl BundlePolicy {
+    fn nick(&self) -> &'static str {
+        match self {
+            BundlePolicy::None => "none",
+            BundlePolicy::Balanced => "balanced",
+            BundlePolicy::MaxCompat => "max-compat",
+            BundlePolicy::MaxBundle => "max-bundle",
+        }
+    }
+}
+
+/// Forward error correction of a transceiver; see the transceiver's `fec-type` property
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FecType {
+    None,
+    /// ulpfec (RFC 5109) packets, carried in red (RFC 2198)
+    UlpRed,
+}
+
+impl Default for FecType {
+    fn default() -> Self {
+        FecType::None
+    }
+}
+
+impl FecType {
+    fn nick(&self) -> &'static str {
+        match self {
+            FecType::None => "none",
+            FecType::UlpRed => "ulp-red",
+        }
+    }
+}
+
+/// Per-session options, provided as query parameters when requesting an offer
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SessionOptions {
+    /// recvonly sessions have no test sources; sendonly sessions ignore incoming media
+    pub direction: MediaDirection,
+    /// resolve remote `.local` host candidates with mdns before adding them to webrtcbin
+    pub resolve_mdns: bool,
+    /// replace the addresses of local host candidates with published `.local` names
+    pub mdns_host_candidates: bool,
+    pub bundle_policy: BundlePolicy,
+    /// offer `a=rtcp-mux`; without it, rtcp is negotiated on a separate component
+    pub rtcp_mux: bool,
+    /// sdp transforms applied to the sent and received descriptions, see `munging`
+    #[serde(deserialize_with = "munging::deserialize")]
+    pub munge: Vec<SdpTransform>,
+    /// offer `nack` feedback and rtx, so that lost packets are retransmitted
+    pub nack: bool,
+    /// forward error correction of the video transceivers
+    pub fec: FecType,
+    /// the ulpfec packets sent, as a percentage of the media packets
+    pub fec_percentage: u32,
+    /// also offer red, and ulpfec, on the audio transceivers
+    pub audio_red: bool,
+    /// negotiate transport-wide cc and adapt the video to the estimated bandwidth
+    pub congestion_control: bool,
+    /// the rids of the video layers offered to receive with simulcast, e.g. `h,m,l`
+    #[serde(deserialize_with = "simulcast::deserialize")]
+    pub simulcast: Vec<String>,
+    /// the rtp header extensions to send, e.g. `mid,audio-level`
+    #[serde(deserialize_with = "extmap::deserialize")]
+    pub header_extensions: Vec<HeaderExtension>,
+    /// detect speaking in incoming audio with webrtcdsp's voice activity detection, instead
+    /// of by its level
+    pub vad: bool,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        SessionOptions {
+            direction: MediaDirection::default(),
+            resolve_mdns: true,
+            mdns_host_candidates: false,
+            bundle_policy: BundlePolicy::default(),
+            rtcp_mux: true,
+            munge: vec![],
+            nack: false,
+            fec: FecType::default(),
+            fec_percentage: DEFAULT_FEC_PERCENTAGE,
+            audio_red: false,
+            congestion_control: false,
+            simulcast: vec![],
+            header_extensions: vec![],
+            vad: false,
+        }
+    }
+}
+
+/// Test sources that can be added to a running session
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TestSource {
+    Video,
+    Screen,
+    Audio,
+}
+
+impl TestSource {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "video" => Some(TestSource::Video),
+            "screen" => Some(TestSource::Screen),
+            "audio" => Some(TestSource::Audio),
+            _ => None,
+        }
+    }
+
+    /// The kind of media of this source
+    fn kind(&self) -> &'static str {
+        match self {
+            TestSource::Video | TestSource::Screen => "video",
+            TestSource::Audio => "audio",
+        }
+    }
+
+    /// The encoding branch for this source, ending in the payloader
+    fn launch_description(&self) -> &'static str {
+        match self {
+            TestSource::Video => {
+                "videotestsrc pattern=ball is-live=true ! videoscale ! capsfilter name=video-scale ! \
+                vp8enc name=video-encoder deadline=1 ! rtpvp8pay pt=96 ! \
+                capsfilter name=rtp-caps caps=application/x-rtp,rtcp-fb-ccm-fir=true"
+            }
+            TestSource::Screen => {
+                "videotestsrc pattern=smpte is-live=true ! video/x-raw,width=1280,height=720,framerate=5/1 ! \
+                videoscale ! capsfilter name=video-scale ! vp8enc name=video-encoder deadline=1 ! \
+                rtpvp8pay pt=96 ! capsfilter name=rtp-caps caps=application/x-rtp,rtcp-fb-ccm-fir=true"
+            }
+            TestSource::Audio => {
+                "audiotestsrc is-live=true ! opusenc ! rtpopuspay pt=97 ! capsfilter name=rtp-caps"
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Peer {
+    pub session_id: u64,
     pub pipeline: gst::Pipeline,
     pub webrtcbin: gst::Element,
+    pub events: Arc<EventBus>,
+    pub local_candidates: Arc<Mutex<Receiver<IceCandidate>>>,
+    pub options: SessionOptions,
+    pub impairments: Arc<SessionImpairments>,
+}
+
+/// Cumulative rtp byte counters, summed over all of a webrtcbin's streams
+#[derive(Debug, Clone, Default)]
+pub struct MediaStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -31,8 +259,19 @@ pub struct IceCandidate {
     pub candidate_str: String,
 }
 
+const VIDEO_RECV_CAPS: &str =
+    "application/x-rtp,media=video,encoding-name=VP8,payload=96,clock-rate=90000";
+const AUDIO_RECV_CAPS: &str =
+    "application/x-rtp,media=audio,encoding-name=OPUS,payload=97,clock-rate=48000";
+
+const MDNS_RESOLVE_TIMEOUT_MS: u64 = 1000;
+
+// webrtcbin's default
+const DEFAULT_FEC_PERCENTAGE: u32 = 100;
+
 const PREFIX_ATTRIBUTE: &str = "a=";
 const PREFIX_ATTRIBUTE_CANDIDATE: &str = "a=candidate";
+const PREFIX_END_OF_CANDIDATES: &str = "a=end-of-candidates";
 
 impl IceCandidate {
     pub fn new(media_line_index: u32, candidate_str: String) -> Self {
@@ -49,6 +288,20 @@ impl IceCandidate {
     pub fn get_candidate_str(&self) -> String {
         self.candidate_str.clone()
     }
+
+    /// Signals that gathering has completed for the media line; like an empty candidate
+    /// in a trickle ice message, or `a=end-of-candidates` in an sdp
+    pub fn end_of_candidates(media_line_index: u32) -> Self {
+        IceCandidate::new(media_line_index, String::new())
+    }
+
+    pub fn is_end_of_candidates(&self) -> bool {
+        self.candidate_str.is_empty()
+    }
+}
+
+pub fn next_session_id() -> u64 {
+    NEXT_SESSION_ID.fetch_add(1, Ordering::SeqCst)
 }
 
 fn create_pipeline(source: &str) -> Result<gst::Pipeline> {
@@ -58,11 +311,41 @@ fn create_pipeline(source: &str) -> Result<gst::Pipeline> {
 }
 
 pub fn create_send_receive_pipeline(
+    events: Arc<EventBus>,
+    impairments: Arc<SessionImpairments>,
+    options: &SessionOptions,
 ) -> StdResult<(gst::Pipeline, gst::Element, Receiver<IceCandidate>), String> {
-    let pipe_source =
-        "videotestsrc pattern=ball is-live=true ! vp8enc deadline=1 ! rtpvp8pay pt=96 ! webrtcbin. \
-        audiotestsrc is-live=true ! opusenc ! rtpopuspay pt=97 ! webrtcbin. \
-        webrtcbin name=webrtcbin";
+    build_send_receive_pipeline(events, impairments, options, true)
+}
+
+/// Like `create_send_receive_pipeline`, but for a session that answers offers from the
+/// remote party; webrtcbin does not create offers of its own when negotiation is needed
+pub fn create_answering_pipeline(
+    events: Arc<EventBus>,
+    impairments: Arc<SessionImpairments>,
+    options: &SessionOptions,
+) -> StdResult<(gst::Pipeline, gst::Element, Receiver<IceCandidate>), String> {
+    build_send_receive_pipeline(events, impairments, options, false)
+}
+
+fn build_send_receive_pipeline(
+    events: Arc<EventBus>,
+    impairments: Arc<SessionImpairments>,
+    options: &SessionOptions,
+    create_offers: bool,
+) -> StdResult<(gst::Pipeline, gst::Element, Receiver<IceCandidate>), String> {
+    let pipe_source = match options.direction {
+        // a pure receiver has no test sources; its transceivers are added below
+        MediaDirection::RecvOnly => "webrtcbin name=webrtcbin",
+        _ => {
+            "videotestsrc pattern=ball is-live=true ! videoscale ! capsfilter name=video-scale ! \
+            vp8enc name=video-encoder deadline=1 ! rtpvp8pay pt=96 ! \
+            capsfilter name=rtp-caps-video caps=application/x-rtp,rtcp-fb-ccm-fir=true ! webrtcbin. \
+            audiotestsrc is-live=true ! opusenc ! rtpopuspay pt=97 ! capsfilter name=rtp-caps-audio ! \
+            webrtcbin. \
+            webrtcbin name=webrtcbin"
+        }
+    };
 
     let pipeline = match create_pipeline(pipe_source) {
         Ok(r) => Ok(r),
@@ -73,20 +356,52 @@ pub fn create_send_receive_pipeline(
         .get_by_name("webrtcbin")
         .expect("Could not find webrtcbin element");
 
-    webrtcbin.set_property_from_str("bundle-policy", "max-bundle");
+    webrtcbin.set_property_from_str("bundle-policy", options.bundle_policy.nick());
+    impairment::impair_transports(&impairments, &webrtcbin).map_err(|e| e.to_string())?;
+    extmap::negotiate(&pipeline, &options.header_extensions);
+
+    if options.congestion_control {
+        congestion::enable(&pipeline, &webrtcbin, events.clone());
+    }
+
+    match options.direction {
+        MediaDirection::RecvOnly => {
+            add_recv_only_transceiver(&webrtcbin, options, VIDEO_RECV_CAPS);
+            add_recv_only_transceiver(&webrtcbin, options, AUDIO_RECV_CAPS);
+        }
+        MediaDirection::SendRecv => (),
+        direction => {
+            // the transceivers were created when parse_launch linked the payloaders
+            let mut index = 0;
+            while let Some(transceiver) = get_transceiver(&webrtcbin, index) {
+                transceiver
+                    .set_property("direction", &direction.to_transceiver_direction())
+                    .expect("Could not set transceiver direction");
+                index += 1;
+            }
+        }
+    }
+
+    for sinkpad in webrtcbin.get_sink_pads() {
+        if let Err(e) = configure_linked_transceiver(options, &sinkpad) {
+            warn!("Could not configure the transceiver of {}: {}", sinkpad.get_name(), e);
+        }
+    }
 
     pipeline.call_async(|p| {
         p.set_state(gst::State::Playing)
             .expect("Couldn't set pipeline to Playing");
         info!("Started webrtc pipeline.");
     });
+    METRICS.session_started();
 
     // setup the ice candidate channels
     let (ice_tx, ice_rx): (Sender<IceCandidate>, Receiver<IceCandidate>) = mpsc::channel();
 
     // bind and listen for candidates; gathered candidates will be sent on this channel
-    listen_for_local_candidates(&webrtcbin, ice_tx);
+    listen_for_local_candidates(&webrtcbin, ice_tx, options.mdns_host_candidates);
 
+    let negotiation_events = events.clone();
     webrtcbin
         .connect("on-negotiation-needed", false, move |values| {
             let _webrtc = values[0]
@@ -112,19 +427,64 @@ pub fn create_send_receive_pipeline(
                 remote_description.is_some()
             );
 
-            auto_create_offer(&_webrtc)
+            if !create_offers {
+                return None;
+            }
+
+            // once the remote party has answered, any further offer is a renegotiation which
+            // has to be announced to the client, who then fetches it with get_pending_offer
+            let announce = if remote_description.is_some() {
+                Some(negotiation_events.clone())
+            } else {
+                None
+            };
+
+            auto_create_offer(&_webrtc, announce)
                 .expect("Could not automatically create offer.");
             None
         })
         .unwrap();
 
+    // rtpbin turns the remote party's PLI and FIR into keyframe requests for the encoders
+    for sinkpad in webrtcbin.get_sink_pads() {
+        keyframe::watch_requests(&sinkpad, events.clone());
+    }
+    keyframe::force_on_connect(&pipeline, &webrtcbin);
+
     let pad_added_pipeline = pipeline.clone();
+    let pad_added_events = events.clone();
+    let pad_added_options = options.clone();
+    let ignore_incoming = options.direction == MediaDirection::SendOnly;
     webrtcbin.connect_pad_added(move |_webrtc, pad| {
-        on_incoming_stream(&pad_added_pipeline, pad)
+        // the sink pads of sources added later
+        if pad.get_direction() == gst::PadDirection::Sink {
+            keyframe::watch_requests(pad, pad_added_events.clone());
+            return;
+        }
+
+        if ignore_incoming {
+            discard_incoming_stream(&pad_added_pipeline, pad)
+                .expect("Could not discard incoming stream.");
+            info!("Discarding incoming stream on send only session");
+            return;
+        }
+
+        on_incoming_stream(
+            &pad_added_pipeline,
+            &pad_added_events,
+            &pad_added_options,
+            pad,
+        )
             .expect("Could not decode incoming stream.");
         info!("Connected to new pad");
     });
 
+    // publish the level and voice activity of the incoming audio
+    voice::watch(&pipeline, events.clone());
+
+    // publish connection and signaling state changes to the session's event subscribers
+    listen_for_state_changes(&webrtcbin, events);
+
     webrtcbin
         .connect("on-new-transceiver", false, move |values| {
             let _webrtc = values[0]
@@ -136,7 +496,11 @@ pub fn create_send_receive_pipeline(
                 .expect("Invalid argument")
                 .unwrap();
             let mlineindex = transceiver.get_property_mlineindex();
-            info!("New transceiver added; mlineindex = {}", mlineindex);
+            let direction = get_property_nick(&transceiver, "direction").unwrap_or_default();
+            info!(
+                "New transceiver added; mlineindex = {}, direction = {}",
+                mlineindex, direction
+            );
 
             None
         })
@@ -145,7 +509,10 @@ pub fn create_send_receive_pipeline(
     return Ok((pipeline, webrtcbin, ice_rx));
 }
 
-fn auto_create_offer(webrtcbin: &gst::Element) -> Result<()> {
+/// Creates an offer and sets it as the local description. If `announce` is provided, an
+/// `offer-available` event carrying the offer's session version is published once the
+/// local description has been applied.
+fn auto_create_offer(webrtcbin: &gst::Element, announce: Option<Arc<EventBus>>) -> Result<()> {
     let webrtcclone = webrtcbin.clone();
     let promise = gst::Promise::new_with_change_func(move |reply| {
         if let Ok(r) = reply {        
@@ -158,10 +525,24 @@ fn auto_create_offer(webrtcbin: &gst::Element) -> Result<()> {
 
             let raw_offer = offer.get_sdp().as_text().unwrap();
             info!("Webrtcbin emitted offer {}", raw_offer);
+            METRICS.offer_created();
+
+            // the session version from the o= line identifies the offer
+            let version = offer
+                .get_sdp()
+                .get_origin()
+                .and_then(|o| o.sess_version())
+                .unwrap_or("0")
+                .to_string();
+            let applied = gst::Promise::new_with_change_func(move |_| {
+                if let Some(events) = &announce {
+                    events.announce("offer-available", &version);
+                }
+            });
 
             info!("Setting local description from SDP Offer");
             webrtcclone
-                .emit("set-local-description", &[&offer, &None::<gst::Promise>])
+                .emit("set-local-description", &[&offer, &applied])
                 .unwrap();
             
         }
@@ -174,63 +555,694 @@ fn auto_create_offer(webrtcbin: &gst::Element) -> Result<()> {
     Ok(())
 }
 
-pub fn get_offer(webrtcbin: &gst::Element, ice_receiver: Receiver<IceCandidate>) -> Result<String> {
+pub fn get_offer(
+    webrtcbin: &gst::Element,
+    options: &SessionOptions,
+    ice_receiver: &Receiver<IceCandidate>,
+) -> Result<String> {
     let offer = block_get_local_description(webrtcbin)?;
 
+    let raw_offer = offer_simulcast(offer.get_sdp().as_text().unwrap(), options);
+
+    with_local_candidates(&raw_offer, options, ice_receiver)
+}
+
+/// Returns the offer created by webrtcbin during a renegotiation, e.g. after a source has
+/// been added or removed, including any pending local candidates. Returns None if webrtcbin
+/// is not currently waiting for an answer to a local offer.
+pub fn get_pending_offer(
+    webrtcbin: &gst::Element,
+    options: &SessionOptions,
+    ice_receiver: &Receiver<IceCandidate>,
+) -> Result<Option<String>> {
+    if get_property_nick(webrtcbin, "signaling-state").as_deref() != Some("have-local-offer") {
+        return Ok(None);
+    }
+
+    let offer = block_get_local_description(webrtcbin)?;
+    let raw_offer = offer_simulcast(offer.get_sdp().as_text().unwrap(), options);
+
+    with_local_candidates(&raw_offer, options, ice_receiver).map(Some)
+}
+
+/// Adds a new test source to a running pipeline and links it to a new webrtcbin sink pad.
+/// Webrtcbin creates a transceiver for the pad and signals that negotiation is needed,
+/// which produces the offer that is returned by `get_pending_offer`.
+/// Returns the name of the source, which is used to remove it again.
+pub fn add_test_source(
+    pipeline: &gst::Pipeline,
+    webrtcbin: &gst::Element,
+    options: &SessionOptions,
+    source: TestSource,
+) -> Result<String> {
+    let bin = gst::parse_bin_from_description(source.launch_description(), true)?;
+    let name = format!(
+        "source_{}",
+        NEXT_SOURCE_ID.fetch_add(1, Ordering::SeqCst)
+    );
+    bin.set_name(&name)?;
+
+    pipeline.add(&bin)?;
+
+    let sinkpad = match link_test_source(pipeline, webrtcbin, options, source, &bin) {
+        Ok(sinkpad) => sinkpad,
+        Err(e) => {
+            pipeline.remove(&bin)?;
+            return Err(e);
+        }
+    };
+
+    bin.sync_state_with_parent()?;
+    info!("Added {:?} source {} on {}", source, name, sinkpad.get_name());
+
+    Ok(name)
+}
+
+/// Links a source bin to a new webrtcbin sink pad. Linking makes webrtcbin schedule the
+/// negotiation, so the new transceiver and the rtp caps are configured before.
+fn link_test_source(
+    pipeline: &gst::Pipeline,
+    webrtcbin: &gst::Element,
+    options: &SessionOptions,
+    source: TestSource,
+    bin: &gst::Bin,
+) -> Result<gst::Pad> {
+    let srcpad = bin
+        .get_static_pad("src")
+        .ok_or_else(|| anyhow!("Source bin has no src pad"))?;
+    let sinkpad = webrtcbin
+        .get_request_pad("sink_%u")
+        .ok_or_else(|| anyhow!("Could not request sink pad from webrtcbin"))?;
+
+    let linked = get_transceiver_for_pad(&sinkpad)
+        .ok_or_else(|| anyhow!("No transceiver for {}", sinkpad.get_name()))
+        .and_then(|transceiver| configure_resilience(&transceiver, options, source.kind()))
+        .and_then(|_| {
+            extmap::negotiate(pipeline, &options.header_extensions);
+            if options.congestion_control {
+                congestion::negotiate_twcc(pipeline);
+            }
+
+            srcpad
+                .link(&sinkpad)
+                .map_err(|e| anyhow!("Could not link source to webrtcbin: {:?}", e))
+        });
+
+    match linked {
+        Ok(_) => Ok(sinkpad),
+        Err(e) => {
+            webrtcbin.release_request_pad(&sinkpad);
+            Err(e)
+        }
+    }
+}
+
+/// Removes a source previously added with `add_test_source`. The transceiver stops sending,
+/// and releasing the webrtcbin sink pad triggers a renegotiation.
+pub fn remove_test_source(pipeline: &gst::Pipeline, webrtcbin: &gst::Element, name: &str) -> Result<()> {
+    let bin = pipeline
+        .get_by_name(name)
+        .filter(|e| e.get_name().starts_with("source_"))
+        .ok_or_else(|| anyhow!("No source named {}", name))?;
+
+    let srcpad = bin
+        .get_static_pad("src")
+        .ok_or_else(|| anyhow!("Source bin has no src pad"))?;
+    let sinkpad = srcpad
+        .get_peer()
+        .ok_or_else(|| anyhow!("Source {} is not linked to webrtcbin", name))?;
+
+    // webrtcbin only considers negotiation necessary when the transceiver's direction
+    // differs from the current description
+    if let Some(transceiver) = get_transceiver_for_pad(&sinkpad) {
+        let direction = transceiver
+            .get_property("direction")?
+            .get_some::<gst_webrtc::WebRTCRTPTransceiverDirection>()?;
+        let stopped = match direction {
+            gst_webrtc::WebRTCRTPTransceiverDirection::Sendrecv => {
+                gst_webrtc::WebRTCRTPTransceiverDirection::Recvonly
+            }
+            _ => gst_webrtc::WebRTCRTPTransceiverDirection::Inactive,
+        };
+        transceiver.set_property("direction", &stopped)?;
+    }
+
+    bin.set_state(gst::State::Null)?;
+    srcpad.unlink(&sinkpad)?;
+    pipeline.remove(&bin)?;
+    webrtcbin.release_request_pad(&sinkpad);
+
+    info!("Removed source {}", name);
+
+    Ok(())
+}
+
+/// Changes the direction of the transceiver negotiated on the given media line of a running
+/// session and creates a new offer. Webrtcbin does not signal that negotiation is needed when
+/// only a direction changes, so the offer is created here and announced with `offer-available`.
+pub fn set_transceiver_direction(
+    webrtcbin: &gst::Element,
+    events: &Arc<EventBus>,
+    mline: u32,
+    direction: MediaDirection,
+) -> Result<()> {
+    let transceiver = get_transceiver_for_mline(webrtcbin, mline)
+        .ok_or_else(|| anyhow!("No transceiver on mline {}", mline))?;
+
+    transceiver.set_property("direction", &direction.to_transceiver_direction())?;
+    info!("Transceiver on mline {} direction set to {:?}", mline, direction);
+
+    auto_create_offer(webrtcbin, Some(events.clone()))
+}
+
+/// Looks up the transceiver belonging to one of webrtcbin's request sink pads, from the
+/// pad's `transceiver` property. The number in the pad's name is not the transceiver's index
+/// once a source was removed, or when the remote party created the transceivers.
+fn get_transceiver_for_pad(pad: &gst::Pad) -> Option<gst_webrtc::WebRTCRTPTransceiver> {
+    pad.get_property("transceiver")
+        .ok()
+        .and_then(|v| v.get::<gst_webrtc::WebRTCRTPTransceiver>().ok().flatten())
+}
+
+/// Looks up the transceiver associated with a media line. Transceivers are numbered in the
+/// order they were created, which differs from the order of the media lines when the remote
+/// party made the offer.
+fn get_transceiver_for_mline(
+    webrtcbin: &gst::Element,
+    mline: u32,
+) -> Option<gst_webrtc::WebRTCRTPTransceiver> {
+    (0..)
+        .map(|index| get_transceiver(webrtcbin, index))
+        .take_while(Option::is_some)
+        .flatten()
+        .find(|transceiver| {
+            transceiver
+                .get_property("mlineindex")
+                .ok()
+                .and_then(|v| v.get_some::<u32>().ok())
+                == Some(mline)
+        })
+}
+
+fn get_transceiver(webrtcbin: &gst::Element, index: i32) -> Option<gst_webrtc::WebRTCRTPTransceiver> {
+    webrtcbin
+        .emit("get-transceiver", &[&index])
+        .ok()
+        .flatten()
+        .and_then(|v| v.get::<gst_webrtc::WebRTCRTPTransceiver>().ok().flatten())
+}
+
+/// Adds a transceiver that only receives media of the given caps; used by sessions that
+/// have no local sources
+fn add_recv_only_transceiver(webrtcbin: &gst::Element, options: &SessionOptions, caps: &str) {
+    let mut caps = gst::Caps::from_str(caps).expect("Invalid transceiver caps");
+    let kind = caps
+        .get_structure(0)
+        .and_then(|s| s.get::<String>("media").ok().flatten())
+        .unwrap_or_default();
+    extmap::add_extmaps(&mut caps, &kind, &options.header_extensions);
+    if options.congestion_control {
+        congestion::add_twcc_extmap(&mut caps);
+    }
+    let transceiver = webrtcbin
+        .emit(
+            "add-transceiver",
+            &[&gst_webrtc::WebRTCRTPTransceiverDirection::Recvonly, &caps],
+        )
+        .expect("Could not add transceiver")
+        .and_then(|v| v.get::<gst_webrtc::WebRTCRTPTransceiver>().ok().flatten())
+        .expect("Webrtcbin did not return the new transceiver");
+
+    if let Err(e) = configure_resilience(&transceiver, options, &kind) {
+        warn!("Could not configure the recvonly transceiver: {}", e);
+    }
+}
+
+/// Configures the transceiver of one of webrtcbin's sink pads for the media linked to it
+fn configure_linked_transceiver(options: &SessionOptions, sinkpad: &gst::Pad) -> Result<()> {
+    let transceiver = get_transceiver_for_pad(sinkpad)
+        .ok_or_else(|| anyhow!("No transceiver for {}", sinkpad.get_name()))?;
+    let kind = sinkpad
+        .peer_query_caps(None)
+        .and_then(|caps| {
+            caps.get_structure(0)
+                .and_then(|s| s.get::<String>("media").ok().flatten())
+        })
+        .ok_or_else(|| anyhow!("No media linked to {}", sinkpad.get_name()))?;
+
+    configure_resilience(&transceiver, options, &kind)
+}
+
+/// Applies the session's retransmission and forward error correction settings to a
+/// transceiver before it is negotiated. Webrtcbin adds the matching rtx, red and ulpfec
+/// payloads and `nack` feedback to the descriptions it creates.
+fn configure_resilience(
+    transceiver: &gst_webrtc::WebRTCRTPTransceiver,
+    options: &SessionOptions,
+    kind: &str,
+) -> Result<()> {
+    let fec = match kind {
+        "video" => options.fec,
+        "audio" if options.audio_red => FecType::UlpRed,
+        _ => FecType::None,
+    };
+
+    transceiver.set_property("do-nack", &options.nack)?;
+    transceiver.set_property_from_str("fec-type", fec.nick());
+    transceiver.set_property("fec-percentage", &options.fec_percentage)?;
+    info!(
+        "Configured {} transceiver; nack = {}, fec = {:?}",
+        kind, options.nack, fec
+    );
+
+    Ok(())
+}
+
+/// Creates a new offer on an existing session with the `ice-restart` option set, so that
+/// webrtcbin generates new ice credentials and gathers a fresh set of candidates. The
+/// pipeline and its transceivers are left untouched. The returned offer contains the
+/// newly gathered candidates, just like the initial offer produced by `get_offer`.
+pub fn restart_ice(
+    webrtcbin: &gst::Element,
+    options: &SessionOptions,
+    ice_receiver: &Receiver<IceCandidate>,
+) -> Result<String> {
+    // candidates from the previous round of gathering no longer apply
+    drain_local_candidates(ice_receiver);
+
+    let restart = gst::Structure::builder("options")
+        .field("ice-restart", &true)
+        .build();
+    let offer = block_create_description(webrtcbin, "create-offer", Some(restart))?;
+    METRICS.offer_created();
+
     let raw_offer = offer.get_sdp().as_text().unwrap();
+    info!("Webrtcbin emitted ice restart offer {}", raw_offer);
+
+    block_set_description(webrtcbin, "set-local-description", &offer)?;
+
+    let raw_offer = offer_simulcast(raw_offer, options);
+    with_local_candidates(&raw_offer, options, ice_receiver)
+}
+
+/// Applies an offer from the remote party to an existing session, e.g. when the browser
+/// restarts ice after a network change, and returns the answer including local candidates.
+pub fn process_sdp_offer(
+    webrtcbin: &gst::Element,
+    options: &SessionOptions,
+    raw_sdp: String,
+    ice_receiver: &Receiver<IceCandidate>,
+) -> Result<String> {
+    let raw_answer = answer_sdp_offer(webrtcbin, options, raw_sdp, ice_receiver)?;
+
+    with_local_candidates(&raw_answer, options, ice_receiver)
+}
+
+/// Applies an offer from the remote party and returns webrtcbin's answer without local
+/// candidates, which are left on `ice_receiver` to be trickled.
+pub fn answer_sdp_offer(
+    webrtcbin: &gst::Element,
+    options: &SessionOptions,
+    raw_sdp: String,
+    ice_receiver: &Receiver<IceCandidate>,
+) -> Result<String> {
+    info!("Processing sdp offer: {}", raw_sdp);
+    let session = validation::validate(&raw_sdp, None).map_err(anyhow::Error::new)?;
+    let raw_sdp = munge_remote_sdp(raw_sdp, session, options);
+
+    let ret = gst_sdp::SDPMessage::parse_buffer(raw_sdp.as_bytes())
+        .map_err(|_| anyhow!("Failed to parse SDP offer"))?;
+    let offer = gst_webrtc::WebRTCSessionDescription::new(gst_webrtc::WebRTCSDPType::Offer, ret);
+
+    drain_local_candidates(ice_receiver);
+    block_set_description(webrtcbin, "set-remote-description", &offer)?;
+    apply_negotiated_extmaps(webrtcbin, &raw_sdp);
+
+    for c in extract_candidates(&raw_sdp) {
+        add_remote_candidate(webrtcbin, options, c.media_line_index, &c.candidate_str)?;
+    }
 
+    let answer = block_create_description(webrtcbin, "create-answer", None)?;
+    let raw_answer = answer.get_sdp().as_text().unwrap();
+    info!("Webrtcbin emitted answer {}", raw_answer);
+
+    block_set_description(webrtcbin, "set-local-description", &answer)?;
+
+    Ok(accept_simulcast(&raw_sdp, raw_answer))
+}
+
+/// Gathers the local candidates and inserts them into the given local description, then
+/// applies the session's transport options that webrtcbin has no property for
+fn with_local_candidates(
+    raw_sdp: &String,
+    options: &SessionOptions,
+    ice_receiver: &Receiver<IceCandidate>,
+) -> Result<String> {
     let local_candidates =
         block_gather_local_candidates(ice_receiver, 16, Duration::from_millis(100));
-    let adjusted_offer = insert_local_candidates_into_sdp(&raw_offer, &local_candidates)?
-        .to_string()
-        .replace("\r\n\r\n", "\r\n");
+    let mut session = insert_local_candidates_into_sdp(raw_sdp, &local_candidates)?;
+
+    if !options.rtcp_mux {
+        // webrtcbin always offers rtcp-mux, but falls back to a separate rtcp component
+        // when the remote description does not include it
+        session.media.iter_mut().for_each(|m| {
+            m.remove_attribute(webrtc_sdp::attribute_type::SdpAttributeType::RtcpMux)
+        });
+    }
+    munging::apply(&mut session, &options.munge, Stage::Send);
+
+    let adjusted_sdp = sdp_text::serialize(raw_sdp, &session);
+
+    Ok(adjusted_sdp)
+}
+
+/// Adds the simulcast layers of the session's options to webrtcbin's offer, which has none
+fn offer_simulcast(raw_offer: String, options: &SessionOptions) -> String {
+    if options.simulcast.is_empty() {
+        return raw_offer;
+    }
+
+    let mut session = match validation::parse(&raw_offer) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Could not add simulcast to the offer: {}", e);
+            return raw_offer;
+        }
+    };
+    if let Err(e) = simulcast::offer(&mut session, &options.simulcast) {
+        warn!("Could not add simulcast to the offer: {}", e);
+        return raw_offer;
+    }
+
+    sdp_text::serialize(&raw_offer, &session)
+}
+
+/// Accepts the simulcast layers of the remote offer in webrtcbin's answer, which has none
+fn accept_simulcast(raw_offer: &str, raw_answer: String) -> String {
+    let offer = match validation::parse(raw_offer) {
+        Ok(s) if simulcast::offered(&s) => s,
+        _ => return raw_answer,
+    };
+
+    let mut answer = match validation::parse(&raw_answer) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Could not accept simulcast in the answer: {}", e);
+            return raw_answer;
+        }
+    };
+    if let Err(e) = simulcast::answer(&offer, &mut answer) {
+        warn!("Could not accept simulcast in the answer: {}", e);
+        return raw_answer;
+    }
+
+    sdp_text::serialize(&raw_answer, &answer)
+}
+
+/// Sends the header extensions of every media line with the ids of the remote description
+fn apply_negotiated_extmaps(webrtcbin: &gst::Element, raw_remote: &str) {
+    let remote = match validation::parse(raw_remote) {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+
+    for sinkpad in webrtcbin.get_sink_pads() {
+        let media = get_transceiver_for_pad(&sinkpad)
+            .and_then(|t| t.get_property("mlineindex").ok())
+            .and_then(|v| v.get_some::<u32>().ok())
+            .and_then(|mline| remote.media.get(mline as usize));
+
+        if let (Some(media), Some(capsfilter)) = (media, extmap::rtp_caps_upstream(&sinkpad)) {
+            extmap::apply_negotiated(&capsfilter, media);
+        }
+    }
+}
+
+/// Applies the session's receive transforms to a validated remote description; the
+/// description is only rewritten when there are any
+fn munge_remote_sdp(
+    raw_sdp: String,
+    mut session: webrtc_sdp::SdpSession,
+    options: &SessionOptions,
+) -> String {
+    if !munging::applies(&options.munge, Stage::Receive) {
+        return raw_sdp;
+    }
+
+    munging::apply(&mut session, &options.munge, Stage::Receive);
+    let munged_sdp = sdp_text::serialize(&raw_sdp, &session);
+    info!("Munged remote sdp: {}", munged_sdp);
+
+    munged_sdp
+}
+
+/// Stops the peer's pipeline; the peer should not be used afterwards
+pub fn close_peer(peer: &Peer) {
+    if let Err(e) = peer.pipeline.set_state(gst::State::Null) {
+        warn!("Could not stop pipeline for session {}: {:?}", peer.session_id, e);
+    }
+    peer.events.close();
+    METRICS.session_ended(peer.session_id);
+    info!("Closed session {}", peer.session_id);
+}
+
+/// Queries webrtcbin for its statistics and sums the rtp byte counters over all
+/// inbound and outbound streams. Blocks until webrtcbin replies.
+pub fn get_media_stats(webrtcbin: &gst::Element) -> Result<MediaStats> {
+    let promise = gst::Promise::new();
+    webrtcbin
+        .emit("get-stats", &[&None::<gst::Pad>, &promise])
+        .unwrap();
+    promise.wait();
+
+    let reply = promise
+        .get_reply()
+        .ok_or_else(|| anyhow!("Webrtcbin did not reply with stats"))?;
+
+    let mut stats = MediaStats::default();
+    for (_, value) in reply.iter() {
+        let entry = match value.get::<gst::Structure>() {
+            Ok(Some(s)) => s,
+            _ => continue,
+        };
+
+        match entry.get_some::<gst_webrtc::WebRTCStatsType>("type") {
+            Ok(gst_webrtc::WebRTCStatsType::OutboundRtp) => {
+                stats.bytes_sent += entry.get_some::<u64>("bytes-sent").unwrap_or(0)
+            }
+            Ok(gst_webrtc::WebRTCStatsType::InboundRtp) => {
+                stats.bytes_received += entry.get_some::<u64>("bytes-received").unwrap_or(0)
+            }
+            _ => (),
+        }
+    }
 
-    Ok(adjusted_offer)
+    Ok(stats)
 }
 
-pub fn process_sdp_answer(webrtcbin: &gst::Element, raw_sdp: String) -> Result<()> {
+pub fn process_sdp_answer(
+    webrtcbin: &gst::Element,
+    options: &SessionOptions,
+    raw_sdp: String,
+) -> Result<()> {
     info!("Processing sdp answer: {}", raw_sdp);
-    validate_sdp(&raw_sdp)?;
+    let offer = get_description(webrtcbin, "local-description")
+        .and_then(|d| d.get_sdp().as_text().ok())
+        .and_then(|raw| validation::parse(&raw).ok());
+    let session = validation::validate(&raw_sdp, offer.as_ref()).map_err(anyhow::Error::new)?;
+    let raw_sdp = munge_remote_sdp(raw_sdp, session, options);
 
     let ret = gst_sdp::SDPMessage::parse_buffer(raw_sdp.as_bytes())
         .map_err(|_| anyhow!("Failed to parse SDP answer"))?;
     let answer = gst_webrtc::WebRTCSessionDescription::new(gst_webrtc::WebRTCSDPType::Answer, ret);
 
-    webrtcbin
-        .emit("set-remote-description", &[&answer, &None::<gst::Promise>])
-        .unwrap();
+    block_set_description(webrtcbin, "set-remote-description", &answer)?;
+    apply_negotiated_extmaps(webrtcbin, &raw_sdp);
 
-    let candidates = extract_candidates(&raw_sdp);
-    candidates.iter().for_each(|candidate| {
-        add_remote_candidate(&webrtcbin, 0, candidate)
-            .unwrap();
-    });
+    for c in extract_candidates(&raw_sdp) {
+        add_remote_candidate(webrtcbin, options, c.media_line_index, &c.candidate_str)?;
+    }
 
     Ok(())
 }
 
 pub fn add_remote_candidate(
     webrtcbin: &gst::Element,
+    options: &SessionOptions,
     media_line_index: u32,
     candidate_str: &str,
 ) -> Result<()> {
-    webrtcbin
-        .emit("add-ice-candidate", &[&media_line_index, &candidate_str])
-        .unwrap();
+    if candidate_str.trim().is_empty() {
+        info!("Remote end-of-candidates for media line {}", media_line_index);
+        webrtcbin.emit("add-ice-candidate", &[&media_line_index, &""])?;
+        return Ok(());
+    }
+
+    let mdns_candidate = match Candidate::from_str(candidate_str) {
+        Ok(c) if options.resolve_mdns && is_mdns_candidate(&c) => c,
+        _ => {
+            webrtcbin.emit("add-ice-candidate", &[&media_line_index, &candidate_str])?;
+            return Ok(());
+        }
+    };
+
+    // the query takes up to a second; the candidate is added once it completes, off the
+    // caller's thread, which may be the server's event loop
+    let webrtcbin = webrtcbin.clone();
+    let raw = candidate_str.to_string();
+    thread::Builder::new()
+        .name("mdns-resolve".to_string())
+        .spawn(move || {
+            let candidate_str = resolve_mdns_candidate(mdns_candidate).unwrap_or(raw);
+            let added = webrtcbin.emit("add-ice-candidate", &[&media_line_index, &candidate_str]);
+            if let Err(e) = added {
+                warn!("Could not add resolved candidate {}: {}", candidate_str, e);
+            }
+        })?;
 
     Ok(())
 }
 
+fn is_mdns_candidate(candidate: &Candidate) -> bool {
+    match &candidate.address {
+        CandidateAddress::Hostname(h) => mdns::is_mdns_hostname(h),
+        _ => false,
+    }
+}
+
+/// Replaces the `.local` hostname of a remote host candidate with the address it resolves
+/// to, since libnice cannot resolve mdns names itself. Returns None if the name does not
+/// resolve.
+fn resolve_mdns_candidate(mut candidate: Candidate) -> Option<String> {
+    let hostname = match &candidate.address {
+        CandidateAddress::Hostname(h) => h.clone(),
+        _ => return None,
+    };
+
+    match mdns::resolve(&hostname, Duration::from_millis(MDNS_RESOLVE_TIMEOUT_MS)) {
+        Ok(ip) => {
+            info!("Resolved mdns candidate {} to {}", hostname, ip);
+            candidate.address = CandidateAddress::Ip(ip);
+            Some(candidate.to_string())
+        }
+        Err(e) => {
+            warn!("Could not resolve mdns candidate: {}", e);
+            None
+        }
+    }
+}
+
+/// Publishes the address of a local ipv4 host candidate as a `.local` name and returns the
+/// candidate using that name instead. Any other candidate is returned unchanged.
+fn publish_mdns_candidate(candidate_str: &str) -> String {
+    let mut candidate = match Candidate::from_str(candidate_str) {
+        Ok(c) => c,
+        Err(_) => return candidate_str.to_string(),
+    };
+
+    let address = match (candidate.candidate_type, &candidate.address) {
+        (CandidateType::Host, CandidateAddress::Ip(std::net::IpAddr::V4(ip))) => *ip,
+        _ => return candidate_str.to_string(),
+    };
+
+    match mdns::publish(address) {
+        Ok(name) => {
+            candidate.address = CandidateAddress::Hostname(name);
+            candidate.to_string()
+        }
+        Err(e) => {
+            warn!("Could not publish mdns name for {}: {}", address, e);
+            candidate_str.to_string()
+        }
+    }
+}
+
+/// Returns the current local and remote descriptions of a session as raw text and as a
+/// structured breakdown, along with the differences between the offer and the answer.
+/// Note that the local description is webrtcbin's, without the local candidates that are
+/// inserted into the sdp sent to the remote party.
+pub fn inspect_session_descriptions(webrtcbin: &gst::Element) -> SessionDescriptions {
+    let describe = |property: &str| {
+        get_description(webrtcbin, property).map(|d| {
+            let sdp_type = match d.get_type() {
+                gst_webrtc::WebRTCSDPType::Offer => "offer",
+                gst_webrtc::WebRTCSDPType::Pranswer => "pranswer",
+                gst_webrtc::WebRTCSDPType::Answer => "answer",
+                gst_webrtc::WebRTCSDPType::Rollback => "rollback",
+                _ => "unknown",
+            };
+            let raw = d.get_sdp().as_text().unwrap_or_default();
+            let parsed = validate_sdp(&raw);
+
+            (sdp_type, raw, parsed)
+        })
+    };
+
+    let local = describe("local-description");
+    let remote = describe("remote-description");
+
+    let diff = match (&local, &remote) {
+        (Some(("offer", _, Ok(offer))), Some(("answer", _, Ok(answer))))
+        | (Some(("answer", _, Ok(answer))), Some(("offer", _, Ok(offer)))) => {
+            Some(inspect::diff(offer, answer))
+        }
+        _ => None,
+    };
+
+    let report = |description: Option<(&str, String, Result<webrtc_sdp::SdpSession>)>| {
+        description.map(|(sdp_type, raw, parsed)| DescriptionReport {
+            sdp_type: sdp_type.to_string(),
+            raw,
+            parsed: parsed.as_ref().ok().map(inspect::summarize),
+            error: parsed.err().map(|e| e.to_string()),
+        })
+    };
+
+    SessionDescriptions {
+        signaling_state: get_property_nick(webrtcbin, "signaling-state"),
+        local: report(local),
+        remote: report(remote),
+        diff,
+    }
+}
+
 /// Uses mozilla's webrtc_sdp library to parse the sdp, kind of an extra layer of protection
-/// around the builting gstreamer webrtc sdp support
+/// around the builting gstreamer webrtc sdp support. The error is a `ValidationReport`
+/// listing every line that could not be parsed.
 fn validate_sdp(sdp: &String) -> Result<webrtc_sdp::SdpSession> {
-    webrtc_sdp::parse_sdp(sdp, false).map_err(|_| anyhow!("The provided sdp is not valid."))
+    validation::parse(sdp).map_err(anyhow::Error::new)
 }
 
-/// Listens for the gathering of local ice candidates
-fn listen_for_local_candidates(webrtcbin: &gst::Element, sender: Sender<IceCandidate>) {
+/// Listens for the gathering of local ice candidates. With `publish_mdns`, host candidates
+/// are rewritten to use published `.local` names instead of their addresses.
+pub(crate) fn listen_for_local_candidates(
+    webrtcbin: &gst::Element,
+    sender: Sender<IceCandidate>,
+    publish_mdns: bool,
+) {
+    let gathering_sender = Mutex::new(sender.clone());
     let shared_sender = Mutex::new(sender);
 
+    // webrtcbin does not emit an empty candidate once gathering completes, so signal the
+    // end of candidates for every media line of the local description when the gathering
+    // state does
+    webrtcbin.connect_notify(Some("ice-gathering-state"), move |_webrtc, _| {
+        if get_property_nick(_webrtc, "ice-gathering-state").as_deref() == Some("complete") {
+            let media_lines = _webrtc
+                .get_property("local-description")
+                .ok()
+                .and_then(|v| v.get::<gst_webrtc::WebRTCSessionDescription>().ok().flatten())
+                .map(|d| d.get_sdp().medias_len())
+                .unwrap_or(1);
+            info!("Local ice candidate gathering complete for {} media lines", media_lines);
+
+            let sender = gathering_sender.lock().unwrap();
+            for index in 0..media_lines {
+                let _ = sender.send(IceCandidate::end_of_candidates(index));
+            }
+        }
+    });
+
     // wire up a candidate receiver
     webrtcbin
         .connect("on-ice-candidate", false, move |values| {
@@ -239,11 +1251,15 @@ fn listen_for_local_candidates(webrtcbin: &gst::Element, sender: Sender<IceCandi
                 .expect("Invalid argument")
                 .expect("Should never be null.");
             let mlineindex = values[1].get_some::<u32>().expect("Invalid argument");
-            let candidate_raw = values[2]
+            let mut candidate_raw = values[2]
                 .get::<String>()
                 .expect("Invalid argument")
                 .unwrap();
 
+            if publish_mdns {
+                candidate_raw = publish_mdns_candidate(&candidate_raw);
+            }
+
             let candidate = IceCandidate::new(mlineindex, candidate_raw.clone());
 
             info!(
@@ -262,9 +1278,52 @@ fn listen_for_local_candidates(webrtcbin: &gst::Element, sender: Sender<IceCandi
         .unwrap();
 }
 
-/// Waits until we have gathered all of the candidates for the local description
+/// Listens for changes to webrtcbin's ice, connection and signaling states; every change
+/// is published as an event named after the property, with the state's nick as the data,
+/// e.g. `ice-connection-state: connected`
+fn listen_for_state_changes(webrtcbin: &gst::Element, events: Arc<EventBus>) {
+    let created_at = Instant::now();
+    // only the first connection of the session counts towards the time to connected
+    let connected = Arc::new(AtomicBool::new(false));
+
+    for property in &[
+        "ice-connection-state",
+        "ice-gathering-state",
+        "connection-state",
+        "signaling-state",
+    ] {
+        let name = *property;
+        let events = events.clone();
+        let connected = connected.clone();
+        webrtcbin.connect_notify(Some(name), move |_webrtc, _| {
+            let state = match get_property_nick(_webrtc, name) {
+                Some(s) => s,
+                None => return,
+            };
+
+            info!("Webrtcbin {} changed: {}", name, state);
+
+            if name == "ice-connection-state" {
+                match state.as_ref() {
+                    "connected" if !connected.swap(true, Ordering::SeqCst) => {
+                        METRICS.ice_connected(created_at.elapsed())
+                    }
+                    "connected" => METRICS.ice_reconnected(),
+                    "failed" => METRICS.ice_failed(),
+                    _ => (),
+                }
+            }
+
+            events.publish(name, &state);
+        });
+    }
+}
+
+/// Waits until we have gathered all of the candidates for the local description; stops
+/// early when gathering completes, in which case the end-of-candidates of every media line
+/// is included
 fn block_gather_local_candidates(
-    rx: Receiver<IceCandidate>,
+    rx: &Receiver<IceCandidate>,
     max_items: usize,
     timeout: Duration,
 ) -> Vec<IceCandidate> {
@@ -273,16 +1332,76 @@ fn block_gather_local_candidates(
 
     while continue_polling && (received.len() < max_items) {
         match rx.recv_timeout(timeout) {
-            Ok(c) => received.push(c),
+            Ok(c) => {
+                continue_polling = !c.is_end_of_candidates();
+                received.push(c);
+            }
             _ => {
                 continue_polling = false;
             }
         };
     }
 
+    // the markers of the other media lines are sent along with the first one
+    received.extend(take_end_of_candidates(rx));
+
     received
 }
 
+/// Takes the end-of-candidates markers that are already waiting on the channel
+pub fn take_end_of_candidates(rx: &Receiver<IceCandidate>) -> Vec<IceCandidate> {
+    rx.try_iter().filter(IceCandidate::is_end_of_candidates).collect()
+}
+
+/// Discards any candidates that have been gathered but not yet consumed
+fn drain_local_candidates(rx: &Receiver<IceCandidate>) {
+    let stale = rx.try_iter().count();
+    if stale > 0 {
+        debug!("Discarded {} stale local candidates", stale);
+    }
+}
+
+/// Emits `create-offer` or `create-answer` on webrtcbin and blocks until the resulting
+/// session description is available
+fn block_create_description(
+    webrtcbin: &gst::Element,
+    signal: &str,
+    options: Option<gst::Structure>,
+) -> Result<gst_webrtc::WebRTCSessionDescription> {
+    let promise = gst::Promise::new();
+    webrtcbin.emit(signal, &[&options, &promise]).unwrap();
+    promise.wait();
+
+    let reply = promise
+        .get_reply()
+        .ok_or_else(|| anyhow!("Webrtcbin did not reply to {}", signal))?;
+
+    let field = if signal == "create-offer" { "offer" } else { "answer" };
+    reply
+        .get::<gst_webrtc::WebRTCSessionDescription>(field)
+        .map_err(|_| anyhow!("Reply to {} has an invalid {}", signal, field))?
+        .ok_or_else(|| anyhow!("Reply to {} does not contain an {}", signal, field))
+}
+
+/// Emits `set-local-description` or `set-remote-description` on webrtcbin and blocks until
+/// webrtcbin has applied the description. A description webrtcbin rejects is an error.
+fn block_set_description(
+    webrtcbin: &gst::Element,
+    signal: &str,
+    description: &gst_webrtc::WebRTCSessionDescription,
+) -> Result<()> {
+    let promise = gst::Promise::new();
+    webrtcbin.emit(signal, &[description, &promise]).unwrap();
+    promise.wait();
+
+    match promise.get_reply() {
+        Some(reply) if reply.has_field("error") => {
+            Err(anyhow!("Webrtcbin could not {}: {}", signal, reply))
+        }
+        _ => Ok(()),
+    }
+}
+
 /// Gets the local description from the provided webrtcbin. The local description may have
 /// been set as part of sdp negotiation, but the actual setting is async, so it may not show up
 /// in the "local-description" property. This function blocks indefinitely until the value is non-none
@@ -307,69 +1426,250 @@ fn block_get_local_description(
     Ok(local.unwrap())
 }
 
+/// Returns webrtcbin's "local-description" or "remote-description", if it has one
+fn get_description(
+    webrtcbin: &gst::Element,
+    property: &str,
+) -> Option<gst_webrtc::WebRTCSessionDescription> {
+    webrtcbin
+        .get_property(property)
+        .ok()
+        .and_then(|v| v.get::<gst_webrtc::WebRTCSessionDescription>().ok())
+        .and_then(|d| d)
+}
+
 /// Inserts the provided ice candidates into an sdp payload, respecting the relative media lines.
+/// Candidates for a media line that does not exist in the sdp are dropped. Once gathering has
+/// completed for a media line with candidates, it is marked with `a=end-of-candidates`.
 fn insert_local_candidates_into_sdp(
     raw_sdp: &String,
     local_candidates: &Vec<IceCandidate>,
 ) -> Result<webrtc_sdp::SdpSession> {
     let mut session = validate_sdp(&raw_sdp)?;
 
-    local_candidates.iter().for_each(|c| {
-        let index = c.get_media_line_index() as usize;
-        let mut media = session
-            .media
-            .get(index)
-            .cloned()
-            .expect("Could not find matching media for ice candidate");
-
-        if let Ok(parsed) = moz_ice::to_moz_candidate(&c.get_candidate_str()) {
-            let attribute = webrtc_sdp::attribute_type::SdpAttribute::Candidate(parsed);
+    local_candidates
+        .iter()
+        .filter(|c| !c.is_end_of_candidates())
+        .for_each(|c| {
+            let index = c.get_media_line_index() as usize;
+            let media = match session.media.get_mut(index) {
+                Some(m) => m,
+                None => {
+                    warn!(
+                        "Dropping local ice candidate for unknown media line {}: {}",
+                        index, c.candidate_str
+                    );
+                    return;
+                }
+            };
+
+            match moz_ice::to_moz_candidate(&c.get_candidate_str()) {
+                Ok(parsed) => {
+                    let attribute = webrtc_sdp::attribute_type::SdpAttribute::Candidate(parsed);
+                    if let Err(e) = media.add_attribute(attribute) {
+                        warn!("Could not add local ice candidate to media line {}: {}", index, e);
+                    }
+                }
+                Err(e) => warn!("Dropping unparseable local ice candidate {}: {}", c.candidate_str, e),
+            }
+        });
 
-            if let Ok(_) = media.add_attribute(attribute) {
-                std::mem::replace(&mut session.media[index], media);
+    let completed = local_candidates
+        .iter()
+        .filter(|c| c.is_end_of_candidates())
+        .map(|c| c.get_media_line_index() as usize)
+        .collect::<Vec<_>>();
+    session
+        .media
+        .iter_mut()
+        .enumerate()
+        .filter(|(index, m)| {
+            completed.contains(index)
+                && m.get_attribute(webrtc_sdp::attribute_type::SdpAttributeType::Candidate)
+                    .is_some()
+        })
+        .for_each(|(_, m)| {
+            if let Err(e) =
+                m.add_attribute(webrtc_sdp::attribute_type::SdpAttribute::EndOfCandidates)
+            {
+                warn!("Could not add end-of-candidates: {}", e);
             }
-        }
-    });
+        });
 
     Ok(session)
 }
 
-/// Parses the given sdp for all ice candidates
-/// Returns a vec of simple strings, with the leading a= removed. 
+/// The media lines of an sdp, identified by their `a=mid`, and its `a=group:BUNDLE` groups
+#[derive(Debug, Default)]
+struct MediaLines {
+    mids: Vec<Option<String>>,
+    bundles: Vec<Vec<String>>,
+}
+
+impl MediaLines {
+    fn parse(sdp: &str) -> Self {
+        let mut lines = MediaLines::default();
+
+        sdp.lines().for_each(|l| {
+            if l.starts_with("m=") {
+                lines.mids.push(None);
+            } else if l.starts_with("a=mid:") {
+                if let Some(last) = lines.mids.last_mut() {
+                    *last = Some(l["a=mid:".len()..].trim().to_string());
+                }
+            } else if l.starts_with("a=group:BUNDLE") {
+                lines.bundles.push(
+                    l["a=group:BUNDLE".len()..]
+                        .split_whitespace()
+                        .map(String::from)
+                        .collect(),
+                );
+            }
+        });
+
+        lines
+    }
+
+    fn index_of_mid(&self, mid: &str) -> Option<u32> {
+        self.mids
+            .iter()
+            .position(|m| m.as_deref() == Some(mid))
+            .map(|i| i as u32)
+    }
+
+    /// Returns the media line whose transport carries the given media line: the first
+    /// media line of its bundle group, or the media line itself if it is not bundled
+    fn transport_index(&self, index: u32) -> u32 {
+        let mid = match self.mids.get(index as usize) {
+            Some(Some(mid)) => mid,
+            _ => return index,
+        };
+
+        self.bundles
+            .iter()
+            .find(|group| group.contains(mid))
+            .and_then(|group| group.iter().find_map(|m| self.index_of_mid(m)))
+            .unwrap_or(index)
+    }
+}
+
+/// Maps a remote candidate trickled for the given media line, or for the media line with
+/// the given mid if one is provided, to the media line whose transport it belongs to
+/// according to the bundle groups of the remote description. Without a remote
+/// description, the media line is returned as is.
+pub fn remote_media_line_index(webrtcbin: &gst::Element, media_line_index: u32, mid: Option<&str>) -> u32 {
+    let remote = get_description(webrtcbin, "remote-description")
+        .and_then(|d| d.get_sdp().as_text().ok());
+
+    let lines = match remote {
+        Some(sdp) => MediaLines::parse(&sdp),
+        None => return media_line_index,
+    };
+
+    let index = mid
+        .and_then(|m| lines.index_of_mid(m))
+        .unwrap_or(media_line_index);
+
+    lines.transport_index(index)
+}
+
+/// Parses the given sdp for all ice candidates, along with the media line they belong to.
+/// The candidate strings have the leading a= removed.
 /// For, example, the following sdp:
+///     m=audio 9 UDP/TLS/RTP/SAVPF 111
 ///     a=rtcp:9 IN IP4 0.0.0.0
 ///     a=candidate:3719404024 1 udp 2122260223 192.168.0.91 55827 typ host generation 0 network-id 1 network-cost 10
 ///     a=ice-ufrag:avZ6
-/// yields vec!["candidate:3719404024 1 udp 2122260223 192.168.0.91 55827 typ host generation 0 network-id 1 network-cost 10"]
-/// Ice candidates need to be added to webrtcbin with a media line index, but we're forcing max-bundle, which means
-/// all streams are transmitted over the same connection, which ultimately will be the first media; media line index will be always
-/// be zero in max-bundle
-fn extract_candidates(sdp: &String) -> Vec<String> {
-    let lines = sdp.lines().collect::<Vec<_>>();
-    lines
-        .iter()
-        .filter(|l| l.starts_with(PREFIX_ATTRIBUTE_CANDIDATE))
-        .map(|l| l.trim_start_matches(PREFIX_ATTRIBUTE).to_string())
-        .collect::<Vec<_>>()
+/// yields vec![IceCandidate { media_line_index: 0, candidate_str: "candidate:3719404024 1 udp 2122260223 192.168.0.91 55827 typ host generation 0 network-id 1 network-cost 10" }]
+/// Webrtcbin only creates an ice stream for the first media line of a bundle group, so the
+/// candidates of every bundled media line are attributed to that one, and duplicates dropped.
+/// An `a=end-of-candidates` yields an empty candidate for its media line.
+fn extract_candidates(sdp: &String) -> Vec<IceCandidate> {
+    let media_lines = MediaLines::parse(sdp);
+    let mut candidates: Vec<IceCandidate> = vec![];
+    let mut index: Option<u32> = None;
+
+    sdp.lines().for_each(|l| {
+        if l.starts_with("m=") {
+            index = Some(index.map_or(0, |i| i + 1));
+        } else if l.starts_with(PREFIX_ATTRIBUTE_CANDIDATE) || l.trim() == PREFIX_END_OF_CANDIDATES {
+            let media_line_index = media_lines.transport_index(index.unwrap_or(0));
+            let candidate_str = if l.starts_with(PREFIX_ATTRIBUTE_CANDIDATE) {
+                l.trim_start_matches(PREFIX_ATTRIBUTE).trim().to_string()
+            } else {
+                String::new()
+            };
+
+            let duplicate = candidates.iter().any(|c| {
+                c.media_line_index == media_line_index && c.candidate_str == candidate_str
+            });
+            if !duplicate {
+                candidates.push(IceCandidate::new(media_line_index, candidate_str));
+            }
+        }
+    });
+
+    candidates
 }
 
 /// Called by the pad-added event on webrtcbin; only *after* successful ice negotiation
-fn on_incoming_stream(pipeline: &gst::Pipeline, pad: &gst::Pad) -> Result<()>{
+fn on_incoming_stream(
+    pipeline: &gst::Pipeline,
+    events: &Arc<EventBus>,
+    options: &SessionOptions,
+    pad: &gst::Pad,
+) -> Result<()> {
     if pad.get_direction() != gst::PadDirection::Src {
         return Ok(());
     }
 
+    if let Some(media) = remote_media_line(pad) {
+        extmap::watch_incoming(pad, &media, events.clone());
+    }
+
     let decodebin = gst::ElementFactory::make("decodebin", None).unwrap();
+    keyframe::request_on_loss(&decodebin);
     let pipeclone = pipeline.clone();
+    let stream = pad.get_name().to_string();
+    let vad = options.vad;
     decodebin.connect_pad_added(move |_decodebin, pad| {
-        add_stream_destination(&pipeclone, pad).expect("Could not add stream destination.");
+        add_stream_destination(&pipeclone, pad, &stream, vad)
+            .expect("Could not add stream destination.");
     });
 
     pipeline.add(&decodebin).unwrap();
     decodebin.sync_state_with_parent().unwrap();
+    pad.link(&decodebin.get_static_pad("sink").unwrap())?;
+
+    Ok(())
+}
+
+/// The media line of the remote description an incoming webrtcbin pad belongs to
+fn remote_media_line(pad: &gst::Pad) -> Option<webrtc_sdp::media_type::SdpMedia> {
+    let mline = pad.get_name().trim_start_matches("src_").parse::<u32>().ok()?;
+    let webrtcbin = pad.get_parent_element()?;
+    let remote = get_description(&webrtcbin, "remote-description")?;
+    let mut session = validation::parse(&remote.get_sdp().as_text().ok()?).ok()?;
+
+    if (mline as usize) < session.media.len() {
+        Some(session.media.swap_remove(mline as usize))
+    } else {
+        None
+    }
+}
 
-    let sinkpad = decodebin.get_static_pad("sink").unwrap();
-    pad.link(&sinkpad).unwrap();
+/// Links an incoming stream straight to a fakesink, without decoding it
+pub(crate) fn discard_incoming_stream(pipeline: &gst::Pipeline, pad: &gst::Pad) -> Result<()> {
+    if pad.get_direction() != gst::PadDirection::Src {
+        return Ok(());
+    }
+
+    let sink = gst::ElementFactory::make("fakesink", None)?;
+    pipeline.add(&sink)?;
+    sink.sync_state_with_parent()?;
+
+    let sinkpad = sink.get_static_pad("sink").unwrap();
+    pad.link(&sinkpad)?;
 
     Ok(())
 }
@@ -379,21 +1679,21 @@ fn on_incoming_stream(pipeline: &gst::Pipeline, pad: &gst::Pad) -> Result<()>{
 /// 
 /// The gstwebrtc-demos dumps the media to autovideosink or autoaudiosink, which
 /// demonstrates that media is actually flowing bidirectionally. 
-/// Here's we're just going to dump to fake sinks instead
-fn add_stream_destination(pipeline: &gst::Pipeline, pad: &gst::Pad) -> Result<()> {
+/// Here's we're just going to dump to fake sinks instead, measuring the level of the audio
+/// and keeping the last frame of the video for snapshots
+fn add_stream_destination(
+    pipeline: &gst::Pipeline,
+    pad: &gst::Pad,
+    stream: &str,
+    vad: bool,
+) -> Result<()> {
     let caps = pad.get_current_caps().unwrap();
     let name = caps.get_structure(0).unwrap().get_name();
 
     let sink = if name.starts_with("video/") {
-        gst::parse_bin_from_description(
-            "queue ! videoconvert ! videoscale ! fakesink",
-            true,
-        )?
+        gst::parse_bin_from_description(&snapshot::destination_description(stream), true)?
     } else if name.starts_with("audio/") {
-        gst::parse_bin_from_description(
-            "queue ! audioconvert ! audioresample ! fakesink",
-            true,
-        )?
+        gst::parse_bin_from_description(&voice::destination_description(stream, vad), true)?
     } else {
         println!("Unknown pad {:?}, ignoring", pad);
         return Ok(());
@@ -0,0 +1,124 @@
+// Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
+
+use std::sync::Mutex;
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+
+/// A single named event published by a session, e.g. an ice connection state change
+#[derive(Debug, Clone)]
+pub struct SessionEvent {
+    pub event: String,
+    pub data: String,
+}
+
+impl SessionEvent {
+    pub fn new(event: &str, data: &str) -> Self {
+        SessionEvent {
+            event: event.to_string(),
+            data: data.to_string(),
+        }
+    }
+
+    /// Formats the event as a server-sent-events message
+    pub fn to_sse(&self) -> String {
+        format!("event: {}\ndata: {}\n\n", self.event, self.data)
+    }
+}
+
+/// Fans out the events of a session to any number of subscribers.
+///
+/// The latest value of every published event is retained and replayed to new subscribers,
+/// so a client that subscribes after ice has connected still sees the "connected" state.
+/// Announced events only reach the current subscribers.
+#[derive(Debug, Default)]
+pub struct EventBus {
+    latest: Mutex<Vec<SessionEvent>>,
+    subscribers: Mutex<Vec<UnboundedSender<SessionEvent>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus::default()
+    }
+
+    pub fn publish(&self, event: &str, data: &str) {
+        let e = SessionEvent::new(event, data);
+
+        {
+            let mut latest = self.latest.lock().unwrap();
+            match latest.iter_mut().find(|l| l.event == e.event) {
+                Some(l) => l.data = e.data.clone(),
+                None => latest.push(e.clone()),
+            }
+        }
+
+        self.send(e);
+    }
+
+    /// Publishes an event without retaining it, for something that happened once rather
+    /// than a state, e.g. an offer that a later subscriber may no longer be able to answer
+    pub fn announce(&self, event: &str, data: &str) {
+        self.send(SessionEvent::new(event, data));
+    }
+
+    fn send(&self, e: SessionEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|s| s.unbounded_send(e.clone()).is_ok());
+    }
+
+    pub fn subscribe(&self) -> UnboundedReceiver<SessionEvent> {
+        let (tx, rx) = unbounded();
+
+        self.latest.lock().unwrap().iter().for_each(|e| {
+            let _ = tx.unbounded_send(e.clone());
+        });
+
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Drops all subscribers, which ends their event streams
+    pub fn close(&self) {
+        self.subscribers.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn received(rx: &mut UnboundedReceiver<SessionEvent>) -> Vec<(String, String)> {
+        let mut events = vec![];
+        while let Ok(e) = rx.try_recv() {
+            events.push((e.event, e.data));
+        }
+        events
+    }
+
+    #[test]
+    fn replays_the_latest_state_to_new_subscribers() {
+        let bus = EventBus::new();
+        bus.publish("ice-connection-state", "checking");
+        bus.publish("ice-connection-state", "connected");
+
+        assert_eq!(
+            received(&mut bus.subscribe()),
+            vec![("ice-connection-state".to_string(), "connected".to_string())]
+        );
+    }
+
+    #[test]
+    fn does_not_replay_announced_events() {
+        let bus = EventBus::new();
+        let mut early = bus.subscribe();
+        bus.announce("offer-available", "2");
+
+        assert_eq!(
+            received(&mut early),
+            vec![("offer-available".to_string(), "2".to_string())]
+        );
+        assert!(received(&mut bus.subscribe()).is_empty());
+    }
+}
@@ -0,0 +1,497 @@
+// Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
+
+//! Rtp header extensions (RFC 8285). The extensions a session sends are `extmap-<id>`
+//! fields in the caps after its payloaders: webrtcbin offers the extmaps of its caps, and
+//! payloaders that implement an extension write it. Once the remote party has a
+//! description, the ids are changed to the ones it negotiated. The audio level and video
+//! orientation the remote party sends with incoming streams are published as session events.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use gst::prelude::*;
+use gstreamer as gst;
+
+use serde::{Deserialize, Deserializer};
+use webrtc_sdp::attribute_type::SdpAttribute;
+use webrtc_sdp::media_type::SdpMedia;
+
+use super::congestion::{RTP_CAPS_PREFIX, TWCC_EXTMAP_ID};
+use super::events::EventBus;
+use super::gstlib::update_property;
+
+pub const MID_URI: &str = "urn:ietf:params:rtp-hdrext:sdes:mid";
+pub const ABS_SEND_TIME_URI: &str = "http://www.webrtc.org/experiments/rtp-hdrext/abs-send-time";
+pub const AUDIO_LEVEL_URI: &str = "urn:ietf:params:rtp-hdrext:ssrc-audio-level";
+pub const VIDEO_ORIENTATION_URI: &str = "urn:3gpp:video-orientation";
+
+const EXTMAP_FIELD_PREFIX: &str = "extmap-";
+const MAX_ONE_BYTE_EXTMAP_ID: u32 = 14;
+// the audio level is sent with every packet; publishing it that often floods subscribers
+const AUDIO_LEVEL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A header extension a session can send
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeaderExtension {
+    Mid,
+    AbsSendTime,
+    AudioLevel,
+    VideoOrientation,
+}
+
+impl HeaderExtension {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "mid" => Some(HeaderExtension::Mid),
+            "abs-send-time" => Some(HeaderExtension::AbsSendTime),
+            "audio-level" => Some(HeaderExtension::AudioLevel),
+            "video-orientation" => Some(HeaderExtension::VideoOrientation),
+            _ => None,
+        }
+    }
+
+    pub fn uri(self) -> &'static str {
+        match self {
+            HeaderExtension::Mid => MID_URI,
+            HeaderExtension::AbsSendTime => ABS_SEND_TIME_URI,
+            HeaderExtension::AudioLevel => AUDIO_LEVEL_URI,
+            HeaderExtension::VideoOrientation => VIDEO_ORIENTATION_URI,
+        }
+    }
+
+    /// The id preferably offered for the extension; transport-wide cc has 1
+    fn id(self) -> u32 {
+        match self {
+            HeaderExtension::AbsSendTime => 2,
+            HeaderExtension::AudioLevel => 3,
+            HeaderExtension::Mid => 4,
+            HeaderExtension::VideoOrientation => 13,
+        }
+    }
+
+    fn applies_to(self, kind: &str) -> bool {
+        match self {
+            HeaderExtension::AudioLevel => kind == "audio",
+            HeaderExtension::VideoOrientation => kind == "video",
+            _ => true,
+        }
+    }
+}
+
+/// Parses the header extensions to send, as a comma separated list of names, e.g.
+/// `mid,audio-level`
+pub fn deserialize<'de, D>(deserializer: D) -> std::result::Result<Vec<HeaderExtension>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let spec = String::deserialize(deserializer)?;
+    spec.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            HeaderExtension::from_name(name).ok_or_else(|| {
+                serde::de::Error::custom(format!("Unknown header extension {}", name))
+            })
+        })
+        .collect()
+}
+
+/// Adds the extmaps of the extensions that apply to the media kind to rtp caps, unless the
+/// caps already have them. An extension whose id the caps use for another extension gets a
+/// free one.
+pub fn add_extmaps(caps: &mut gst::Caps, kind: &str, extensions: &[HeaderExtension]) {
+    for structure in caps.get_mut().expect("Caps are not writable").iter_mut() {
+        let mut used = structure
+            .iter()
+            .filter_map(|(field, value)| {
+                if !field.starts_with(EXTMAP_FIELD_PREFIX) {
+                    return None;
+                }
+                let id = field[EXTMAP_FIELD_PREFIX.len()..].parse::<u32>().ok()?;
+                Some((id, value.get::<String>().ok().flatten()?))
+            })
+            .collect::<Vec<_>>();
+
+        for extension in extensions.iter().filter(|e| e.applies_to(kind)) {
+            let uri = extension.uri();
+            if used.iter().any(|(_, u)| u == uri) {
+                continue;
+            }
+
+            let ids = used.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+            match free_id(&ids, extension.id()) {
+                Some(id) => {
+                    structure.set(&format!("{}{}", EXTMAP_FIELD_PREFIX, id), &uri);
+                    used.push((id, uri.to_string()));
+                }
+                None => warn!("No extmap id left for {}", uri),
+            }
+        }
+    }
+}
+
+/// The preferred id if it is not used, or else the first free one-byte id. The id of
+/// transport-wide cc is never handed out, it is added to the caps after the extensions.
+fn free_id(used: &[u32], preferred: u32) -> Option<u32> {
+    if !used.contains(&preferred) {
+        return Some(preferred);
+    }
+    (1..=MAX_ONE_BYTE_EXTMAP_ID).find(|id| *id != TWCC_EXTMAP_ID && !used.contains(id))
+}
+
+/// The caps of an rtp caps filter, keeping the fields it already adds
+pub(crate) fn rtp_caps(capsfilter: &gst::Element) -> gst::Caps {
+    capsfilter
+        .get_property("caps")
+        .ok()
+        .and_then(|v| v.get::<gst::Caps>().ok().flatten())
+        .filter(|c| !c.is_any() && !c.is_empty())
+        .unwrap_or_else(|| gst::Caps::new_simple("application/x-rtp", &[]))
+}
+
+/// The media kind of the payloader an rtp caps filter follows
+fn media_kind(capsfilter: &gst::Element) -> Option<String> {
+    let caps = capsfilter.get_static_pad("sink")?.peer_query_caps(None)?;
+    let structure = caps.get_structure(0)?;
+    structure.get::<String>("media").ok().flatten()
+}
+
+/// Offers the extensions on the rtp streams of the pipeline, e.g. after adding a source
+pub fn negotiate(pipeline: &gst::Pipeline, extensions: &[HeaderExtension]) {
+    pipeline
+        .iterate_recurse()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.get_name().starts_with(RTP_CAPS_PREFIX))
+        .for_each(|e| {
+            let kind = match media_kind(&e) {
+                Some(kind) => kind,
+                None => return warn!("No media kind for {}", e.get_name()),
+            };
+
+            let mut caps = rtp_caps(&e);
+            add_extmaps(&mut caps, &kind, extensions);
+            if let Err(err) = update_property(&e, "caps", caps) {
+                warn!(
+                    "Could not offer header extensions on {}: {}",
+                    e.get_name(),
+                    err
+                );
+            }
+        });
+}
+
+/// The rtp caps filter of the stream linked to one of webrtcbin's sink pads
+pub(crate) fn rtp_caps_upstream(sinkpad: &gst::Pad) -> Option<gst::Element> {
+    let mut pad = sinkpad.get_peer()?;
+    loop {
+        // the src pad of a source bin
+        if let Some(ghost) = pad.downcast_ref::<gst::GhostPad>() {
+            pad = ghost.get_target()?;
+            continue;
+        }
+
+        let element = pad.get_parent_element()?;
+        if element.get_name().starts_with(RTP_CAPS_PREFIX) {
+            return Some(element);
+        }
+        pad = element.get_static_pad("sink")?.get_peer()?;
+    }
+}
+
+fn extmaps(media: &SdpMedia) -> Vec<(u16, &str)> {
+    media
+        .get_attributes()
+        .iter()
+        .filter_map(|a| match a {
+            SdpAttribute::Extmap(e) => Some((e.id, e.url.as_str())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The id of the extension with the uri on a media line, if it has one
+pub(crate) fn extmap_id(media: &SdpMedia, uri: &str) -> Option<u16> {
+    extmaps(media)
+        .into_iter()
+        .find(|(_, url)| *url == uri)
+        .map(|(id, _)| id)
+}
+
+/// Sends the extensions of an rtp caps filter with the ids of the remote description's
+/// media line, and stops sending the ones it does not have
+pub fn apply_negotiated(capsfilter: &gst::Element, media: &SdpMedia) {
+    let negotiated = extmaps(media);
+    let mut caps = rtp_caps(capsfilter);
+
+    for structure in caps.get_mut().expect("Caps are not writable").iter_mut() {
+        let sent = structure
+            .iter()
+            .filter(|(field, _)| field.starts_with(EXTMAP_FIELD_PREFIX))
+            .filter_map(|(field, value)| {
+                let uri = value.get::<String>().ok().flatten()?;
+                Some((field.to_string(), uri))
+            })
+            .collect::<Vec<_>>();
+
+        // all fields go first, a negotiated id may be the one of another sent extension
+        for (field, _) in &sent {
+            structure.remove_field(field);
+        }
+        let uris = sent.into_iter().map(|(_, uri)| uri).collect::<Vec<_>>();
+        for (field, uri) in negotiated_fields(&uris, &negotiated) {
+            structure.set(&field, &uri);
+        }
+    }
+
+    match update_property(capsfilter, "caps", caps) {
+        Ok(true) => debug!("Header extensions of {} negotiated", capsfilter.get_name()),
+        Ok(false) => (),
+        Err(e) => warn!(
+            "Could not negotiate the header extensions of {}: {}",
+            capsfilter.get_name(),
+            e
+        ),
+    }
+}
+
+/// The extmap fields that send the uris with the ids of the remote party, leaving out the
+/// uris it did not negotiate
+fn negotiated_fields(uris: &[String], negotiated: &[(u16, &str)]) -> Vec<(String, String)> {
+    uris.iter()
+        .filter_map(|uri| match negotiated.iter().find(|(_, url)| url == uri) {
+            Some((id, _)) => Some((format!("{}{}", EXTMAP_FIELD_PREFIX, id), uri.clone())),
+            None => {
+                info!("The remote party did not negotiate {}", uri);
+                None
+            }
+        })
+        .collect()
+}
+
+/// The level of an audio level extension, in -dBov, and whether the packet has voice
+pub fn parse_audio_level(data: &[u8]) -> Option<(u8, bool)> {
+    let first = *data.first()?;
+    Some((first & 0x7f, first & 0x80 != 0))
+}
+
+/// The rotation of a video orientation extension, in degrees, and whether it is flipped
+pub fn parse_video_orientation(data: &[u8]) -> Option<(u16, bool)> {
+    let first = *data.first()?;
+    Some((u16::from(first & 0x03) * 90, first & 0x04 != 0))
+}
+
+/// Publishes the audio level and video orientation the remote party sends with an incoming
+/// stream, as the `audio-level` and `video-orientation` events with the name of the pad. The
+/// audio level is published at most every quarter second, and only when it changes.
+pub fn watch_incoming(pad: &gst::Pad, media: &SdpMedia, events: Arc<EventBus>) {
+    let audio_level = extmap_id(media, AUDIO_LEVEL_URI).map(|id| id as u8);
+    let video_orientation = extmap_id(media, VIDEO_ORIENTATION_URI).map(|id| id as u8);
+    if audio_level.is_none() && video_orientation.is_none() {
+        return;
+    }
+
+    let name = pad.get_name().to_string();
+    let last_level = Mutex::new((None, Instant::now() - AUDIO_LEVEL_INTERVAL));
+    let last_orientation = Mutex::new(None);
+
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_pad, info| {
+        let map = match info.data {
+            Some(gst::PadProbeData::Buffer(ref buffer)) => buffer.map_readable().ok(),
+            _ => None,
+        };
+        let packet = match map {
+            Some(ref map) => map.as_slice(),
+            None => return gst::PadProbeReturn::Ok,
+        };
+
+        let level = audio_level
+            .and_then(|id| header_extension(packet, id))
+            .and_then(parse_audio_level);
+        if let Some((level, voice)) = level {
+            let mut last = last_level.lock().unwrap();
+            if last.0 != Some((level, voice)) && last.1.elapsed() >= AUDIO_LEVEL_INTERVAL {
+                *last = (Some((level, voice)), Instant::now());
+                let voice = if voice { " voice" } else { "" };
+                events.publish("audio-level", &format!("{} -{}{}", name, level, voice));
+            }
+        }
+
+        let orientation = video_orientation
+            .and_then(|id| header_extension(packet, id))
+            .and_then(parse_video_orientation);
+        if let Some((rotation, flipped)) = orientation {
+            let mut last = last_orientation.lock().unwrap();
+            if *last != Some((rotation, flipped)) {
+                *last = Some((rotation, flipped));
+                let flipped = if flipped { " flipped" } else { "" };
+                events.publish(
+                    "video-orientation",
+                    &format!("{} {}{}", name, rotation, flipped),
+                );
+            }
+        }
+
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// The payload of a header extension of an rtp packet, in the one-byte or two-byte
+/// format of RFC 8285
+pub fn header_extension(packet: &[u8], id: u8) -> Option<&[u8]> {
+    if packet.len() < 12 || packet[0] >> 6 != 2 || packet[0] & 0x10 == 0 {
+        return None;
+    }
+
+    let start = 12 + 4 * (packet[0] & 0x0f) as usize;
+    let header = packet.get(start..start + 4)?;
+    let profile = u16::from_be_bytes([header[0], header[1]]);
+    let length = 4 * u16::from_be_bytes([header[2], header[3]]) as usize;
+    let mut extensions = packet.get(start + 4..start + 4 + length)?;
+
+    let two_byte = match profile {
+        0xbede => false,
+        p if p & 0xfff0 == 0x1000 => true,
+        _ => return None,
+    };
+
+    while let Some(&first) = extensions.first() {
+        // padding
+        if first == 0 {
+            extensions = &extensions[1..];
+            continue;
+        }
+
+        let (element_id, size, offset) = if two_byte {
+            (first, *extensions.get(1)? as usize, 2)
+        } else {
+            (first >> 4, (first & 0x0f) as usize + 1, 1)
+        };
+        if !two_byte && element_id == 15 {
+            return None;
+        }
+
+        let data = extensions.get(offset..offset + size)?;
+        if element_id == id {
+            return Some(data);
+        }
+        extensions = &extensions[offset + size..];
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::congestion::TWCC_URI;
+    use super::*;
+
+    fn packet(profile: u16, extensions: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x90, 96, 0, 1, 0, 0, 0, 1, 0, 0, 0, 42];
+        packet.extend_from_slice(&profile.to_be_bytes());
+        packet.extend_from_slice(&((extensions.len() / 4) as u16).to_be_bytes());
+        packet.extend_from_slice(extensions);
+        packet.extend_from_slice(&[0xde, 0xad]);
+        packet
+    }
+
+    #[test]
+    fn reads_one_byte_extensions() {
+        // id 1 with 2 bytes, padding, then id 3 with the rid "hi"
+        let packet = packet(0xbede, &[0x11, 0xaa, 0xbb, 0x00, 0x31, b'h', b'i', 0x00]);
+
+        assert_eq!(header_extension(&packet, 1), Some(&[0xaa, 0xbb][..]));
+        assert_eq!(header_extension(&packet, 3), Some(&b"hi"[..]));
+        assert_eq!(header_extension(&packet, 2), None);
+    }
+
+    #[test]
+    fn reads_two_byte_extensions() {
+        let packet = packet(0x1000, &[0x05, 0x01, b'l', 0x00]);
+
+        assert_eq!(header_extension(&packet, 5), Some(&b"l"[..]));
+    }
+
+    #[test]
+    fn ignores_packets_without_extensions() {
+        let mut packet = packet(0xbede, &[0x31, b'h', b'i', 0x00]);
+        packet[0] = 0x80;
+
+        assert_eq!(header_extension(&packet, 3), None);
+    }
+
+    #[test]
+    fn parses_audio_level() {
+        assert_eq!(parse_audio_level(&[0x80 | 30]), Some((30, true)));
+        assert_eq!(parse_audio_level(&[127]), Some((127, false)));
+        assert_eq!(parse_audio_level(&[]), None);
+    }
+
+    #[test]
+    fn parses_video_orientation() {
+        assert_eq!(parse_video_orientation(&[0x00]), Some((0, false)));
+        assert_eq!(parse_video_orientation(&[0x01]), Some((90, false)));
+        // the camera bit is ignored
+        assert_eq!(parse_video_orientation(&[0x0f]), Some((270, true)));
+    }
+
+    #[test]
+    fn finds_negotiated_ids() {
+        let media = webrtc_sdp::parse_sdp(
+            "v=0\r\n\
+            o=- 1 1 IN IP4 0.0.0.0\r\n\
+            s=-\r\n\
+            t=0 0\r\n\
+            m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+            c=IN IP4 0.0.0.0\r\n\
+            a=mid:0\r\n\
+            a=sendrecv\r\n\
+            a=extmap:1 urn:ietf:params:rtp-hdrext:ssrc-audio-level\r\n\
+            a=extmap:9 urn:ietf:params:rtp-hdrext:sdes:mid\r\n\
+            a=rtpmap:111 opus/48000/2\r\n",
+            false,
+        )
+        .expect("Invalid sdp")
+        .media
+        .remove(0);
+
+        assert_eq!(extmap_id(&media, AUDIO_LEVEL_URI), Some(1));
+        assert_eq!(extmap_id(&media, MID_URI), Some(9));
+        assert_eq!(extmap_id(&media, VIDEO_ORIENTATION_URI), None);
+    }
+
+    #[test]
+    fn swaps_ids_to_the_negotiated_ones() {
+        // sent with twcc on 1 and audio level on 3, negotiated the other way around
+        let uris = vec![TWCC_URI.to_string(), AUDIO_LEVEL_URI.to_string()];
+        let negotiated = vec![(1, AUDIO_LEVEL_URI), (3, TWCC_URI)];
+
+        assert_eq!(
+            negotiated_fields(&uris, &negotiated),
+            vec![
+                ("extmap-3".to_string(), TWCC_URI.to_string()),
+                ("extmap-1".to_string(), AUDIO_LEVEL_URI.to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_out_extensions_not_negotiated() {
+        let uris = vec![MID_URI.to_string(), VIDEO_ORIENTATION_URI.to_string()];
+        let negotiated = vec![(9, MID_URI)];
+
+        assert_eq!(
+            negotiated_fields(&uris, &negotiated),
+            vec![("extmap-9".to_string(), MID_URI.to_string())]
+        );
+    }
+
+    #[test]
+    fn picks_a_free_id_when_the_preferred_one_is_used() {
+        assert_eq!(free_id(&[1, 2], 3), Some(3));
+        assert_eq!(free_id(&[1, 2, 3], 3), Some(4));
+        // transport-wide cc is added after the extensions
+        assert_eq!(free_id(&[3], 3), Some(2));
+        assert_eq!(free_id(&(1..=14).collect::<Vec<_>>(), 3), None);
+    }
+}
@@ -3,6 +3,32 @@
 use gst::prelude::*;
 use gstreamer as gst;
 
+use anyhow::Result;
+
+/// Reads an enum property from the object, returning the nick of its current value,
+/// e.g. "connected" for an ice-connection-state of GST_WEBRTC_ICE_CONNECTION_STATE_CONNECTED
+pub fn get_property_nick<O: glib::ObjectExt>(object: &O, property: &str) -> Option<String> {
+    object
+        .get_property(property)
+        .ok()
+        .and_then(|v| glib::EnumValue::from_value(&v))
+        .map(|v| v.get_nick().to_string())
+}
+
+/// Sets a property unless it already has the value; setting caps renegotiates the stream
+pub fn update_property<T>(element: &gst::Element, property: &str, value: T) -> Result<bool>
+where
+    T: glib::ToSendValue + for<'a> glib::value::FromValueOptional<'a> + PartialEq,
+{
+    let current = element.get_property(property)?.get::<T>()?;
+    if current.as_ref() == Some(&value) {
+        return Ok(false);
+    }
+
+    element.set_property(property, &value)?;
+    Ok(true)
+}
+
 pub trait ToPipeline {
     fn to_pipeline(&self) -> gst::Pipeline;
 }
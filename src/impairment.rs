@@ -0,0 +1,386 @@
+// Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
+
+//! Network impairment of a session's media, to see how the jitterbuffer, nack and fec cope
+//! with loss and delay. Netsim elements sit inside webrtcbin, between its rtpbin and the
+//! transports: packets lost on the send path include retransmissions and fec packets, and
+//! packets lost on the receive path are missing by the time they reach the jitterbuffer.
+
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+use gst::prelude::*;
+use gstreamer as gst;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+const SEND_PREFIX: &str = "impair-send";
+const RECEIVE_PREFIX: &str = "impair-receive";
+
+/// rtpbin's pads towards the transports; the suffix is the rtp session
+const SEND_RTP_SRC_PREFIX: &str = "send_rtp_src_";
+const RECV_RTP_SINK_PREFIX: &str = "recv_rtp_sink_";
+
+/// The impairment of one direction of a session's media; the default impairs nothing
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Impairment {
+    /// probability of dropping a packet, from 0 to 1
+    pub loss: f32,
+    /// delay added to every packet, in milliseconds
+    pub delay_ms: u32,
+    /// random delay added on top of `delay_ms`, up to this many milliseconds
+    pub jitter_ms: u32,
+    /// bandwidth cap in kbps; 0 is unlimited
+    pub max_kbps: u32,
+    /// lets delayed packets overtake each other
+    pub reorder: bool,
+}
+
+impl Impairment {
+    /// Checks that netsim can apply the impairment: a loss probability, and delays and a
+    /// bandwidth cap that fit its signed properties
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.loss) {
+            return Err(anyhow!("loss {} is not between 0 and 1", self.loss));
+        }
+        let max_delay = self
+            .delay_ms
+            .checked_add(self.jitter_ms)
+            .and_then(|d| i32::try_from(d).ok());
+        if max_delay.is_none() {
+            return Err(anyhow!(
+                "delay_ms {} and jitter_ms {} add up to more than {} ms",
+                self.delay_ms,
+                self.jitter_ms,
+                std::i32::MAX
+            ));
+        }
+        if i32::try_from(self.max_kbps).is_err() {
+            return Err(anyhow!(
+                "max_kbps {} is more than {}",
+                self.max_kbps,
+                std::i32::MAX
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MediaPath {
+    Send,
+    Receive,
+}
+
+impl MediaPath {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "send" => Some(MediaPath::Send),
+            "receive" => Some(MediaPath::Receive),
+            _ => None,
+        }
+    }
+
+    /// The name prefix of the netsim elements on this path
+    fn prefix(self) -> &'static str {
+        match self {
+            MediaPath::Send => SEND_PREFIX,
+            MediaPath::Receive => RECEIVE_PREFIX,
+        }
+    }
+}
+
+/// The impairments of a session, in both directions
+#[derive(Debug, Default, Serialize)]
+pub struct Impairments {
+    pub send: Impairment,
+    pub receive: Impairment,
+}
+
+/// The impairments of a session, shared by the session and the streams it creates later
+#[derive(Debug, Default)]
+pub struct SessionImpairments {
+    current: Mutex<Impairments>,
+}
+
+impl SessionImpairments {
+    pub fn new() -> Self {
+        SessionImpairments::default()
+    }
+
+    pub fn get(&self) -> Impairments {
+        let current = self.current.lock().unwrap();
+        Impairments {
+            send: current.send.clone(),
+            receive: current.receive.clone(),
+        }
+    }
+
+    /// Changes the impairment of a path, including its running streams
+    pub fn set(&self, pipeline: &gst::Pipeline, path: MediaPath, impairment: Impairment) {
+        info!("Impairing the {:?} path: {:?}", path, impairment);
+        {
+            let mut current = self.current.lock().unwrap();
+            match path {
+                MediaPath::Send => current.send = impairment,
+                MediaPath::Receive => current.receive = impairment,
+            }
+        }
+
+        self.apply(pipeline);
+    }
+
+    /// Configures every netsim element of the pipeline, including those inside webrtcbin
+    pub fn apply(&self, pipeline: &gst::Pipeline) {
+        let current = self.get();
+
+        pipeline
+            .iterate_recurse()
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .for_each(|e| {
+                let name = e.get_name();
+                if name.starts_with(SEND_PREFIX) {
+                    configure(&e, &current.send);
+                } else if name.starts_with(RECEIVE_PREFIX) {
+                    configure(&e, &current.receive);
+                }
+            });
+    }
+
+    /// Creates a netsim element for a new stream on the path, with the current impairment
+    fn create_element(&self, path: MediaPath, stream: &str) -> Result<gst::Element> {
+        let name = format!("{}-{}", path.prefix(), stream);
+        let netsim = gst::ElementFactory::make("netsim", Some(&name))?;
+
+        let current = self.get();
+        configure(
+            &netsim,
+            match path {
+                MediaPath::Send => &current.send,
+                MediaPath::Receive => &current.receive,
+            },
+        );
+
+        Ok(netsim)
+    }
+}
+
+/// Impairs the rtp sessions of webrtcbin's transports, including the ones created by later
+/// negotiations. Webrtcbin links its rtpbin to a transport when a description is applied,
+/// so a netsim element is put into every such link as soon as it is made.
+pub fn impair_transports(
+    impairments: &Arc<SessionImpairments>,
+    webrtcbin: &gst::Element,
+) -> Result<()> {
+    let bin = webrtcbin
+        .clone()
+        .downcast::<gst::Bin>()
+        .map_err(|_| anyhow!("webrtcbin is not a bin"))?;
+    let rtpbin = bin
+        .iterate_elements()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .find(|e| e.get_factory().map_or(false, |f| f.get_name() == "rtpbin"))
+        .ok_or_else(|| anyhow!("Could not find webrtcbin's rtpbin"))?;
+
+    for pad in rtpbin.get_pads() {
+        watch_transport_link(impairments, &bin, &pad);
+    }
+
+    let impairments = impairments.clone();
+    let weak_bin = bin.downgrade();
+    rtpbin.connect_pad_added(move |_rtpbin, pad| {
+        if let Some(bin) = weak_bin.upgrade() {
+            watch_transport_link(&impairments, &bin, pad);
+        }
+    });
+
+    Ok(())
+}
+
+/// Impairs the link between one of rtpbin's pads and a transport, now if the pad is linked
+/// already, or once it is
+fn watch_transport_link(impairments: &Arc<SessionImpairments>, bin: &gst::Bin, pad: &gst::Pad) {
+    let name = pad.get_name();
+    let (path, session) = if name.starts_with(SEND_RTP_SRC_PREFIX) {
+        (MediaPath::Send, &name[SEND_RTP_SRC_PREFIX.len()..])
+    } else if name.starts_with(RECV_RTP_SINK_PREFIX) {
+        (MediaPath::Receive, &name[RECV_RTP_SINK_PREFIX.len()..])
+    } else {
+        return;
+    };
+    let stream = format!("transport-{}", session);
+
+    if pad.is_linked() {
+        impair_link(impairments, bin, pad, path, &stream);
+    }
+
+    let impairments = impairments.clone();
+    let weak_bin = bin.downgrade();
+    pad.connect_linked(move |pad, _peer| {
+        if let Some(bin) = weak_bin.upgrade() {
+            impair_link(&impairments, &bin, pad, path, &stream);
+        }
+    });
+}
+
+/// Puts a netsim element into the link of one of rtpbin's pads, unless it is linked to one
+/// already. The link is only changed while no data flows through it.
+fn impair_link(
+    impairments: &SessionImpairments,
+    bin: &gst::Bin,
+    pad: &gst::Pad,
+    path: MediaPath,
+    stream: &str,
+) {
+    let peer = match pad.get_peer() {
+        Some(peer) => peer,
+        None => return,
+    };
+    let impaired = peer
+        .get_parent_element()
+        .map_or(false, |e| e.get_name().starts_with(path.prefix()));
+    if impaired {
+        return;
+    }
+
+    let netsim = match impairments.create_element(path, stream).and_then(|netsim| {
+        bin.add(&netsim)?;
+        netsim.sync_state_with_parent()?;
+        Ok(netsim)
+    }) {
+        Ok(netsim) => netsim,
+        Err(e) => {
+            warn!("Could not impair the {:?} path of {}: {}", path, stream, e);
+            return;
+        }
+    };
+
+    let upstream = match path {
+        MediaPath::Send => pad.clone(),
+        MediaPath::Receive => peer,
+    };
+    upstream.add_probe(gst::PadProbeType::IDLE, move |upstream, _info| {
+        if let Err(e) = insert(upstream, &netsim) {
+            warn!("Could not impair {}: {}", netsim.get_name(), e);
+        }
+        gst::PadProbeReturn::Remove
+    });
+}
+
+/// Moves the link of a src pad behind a netsim element
+fn insert(srcpad: &gst::Pad, netsim: &gst::Element) -> Result<()> {
+    let sinkpad = srcpad
+        .get_peer()
+        .ok_or_else(|| anyhow!("{} is no longer linked", srcpad.get_name()))?;
+    let netsim_sink = netsim.get_static_pad("sink").unwrap();
+    let netsim_src = netsim.get_static_pad("src").unwrap();
+
+    srcpad.unlink(&sinkpad)?;
+    srcpad
+        .link(&netsim_sink)
+        .map_err(|e| anyhow!("Could not link to {}: {:?}", netsim.get_name(), e))?;
+    netsim_src
+        .link(&sinkpad)
+        .map_err(|e| anyhow!("Could not link {}: {:?}", netsim.get_name(), e))?;
+
+    info!(
+        "Impairing {} through {}",
+        srcpad.get_name(),
+        netsim.get_name()
+    );
+
+    Ok(())
+}
+
+fn configure(netsim: &gst::Element, impairment: &Impairment) {
+    let delayed = impairment.delay_ms > 0 || impairment.jitter_ms > 0;
+    let max_delay = impairment.delay_ms.saturating_add(impairment.jitter_ms);
+    let max_kbps = match impairment.max_kbps {
+        0 => -1,
+        kbps => saturate(kbps),
+    };
+
+    let results = vec![
+        netsim.set_property("drop-probability", &impairment.loss.max(0.0).min(1.0)),
+        netsim.set_property("delay-probability", &if delayed { 1.0f32 } else { 0.0f32 }),
+        netsim.set_property("min-delay", &saturate(impairment.delay_ms)),
+        netsim.set_property("max-delay", &saturate(max_delay)),
+        netsim.set_property("max-kbps", &max_kbps),
+    ];
+
+    for r in results.into_iter().filter_map(|r| r.err()) {
+        warn!("Could not configure {}: {}", netsim.get_name(), r);
+    }
+
+    // older netsim versions always reorder delayed packets
+    if netsim.find_property("allow-reordering").is_some() {
+        if let Err(e) = netsim.set_property("allow-reordering", &impairment.reorder) {
+            warn!("Could not configure {}: {}", netsim.get_name(), e);
+        }
+    }
+}
+
+/// The value for one of netsim's signed properties, capped at the largest it takes
+fn saturate(value: u32) -> i32 {
+    i32::try_from(value).unwrap_or(std::i32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_impairments_netsim_can_apply() {
+        let impairment = Impairment {
+            loss: 0.05,
+            delay_ms: 50,
+            jitter_ms: 20,
+            max_kbps: 500,
+            reorder: true,
+        };
+
+        assert!(impairment.validate().is_ok());
+        assert!(Impairment::default().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_impairments_out_of_range() {
+        let invalid = [
+            Impairment {
+                loss: 1.5,
+                ..Impairment::default()
+            },
+            Impairment {
+                loss: std::f32::NAN,
+                ..Impairment::default()
+            },
+            Impairment {
+                delay_ms: std::u32::MAX,
+                jitter_ms: 1,
+                ..Impairment::default()
+            },
+            Impairment {
+                delay_ms: 1 << 31,
+                ..Impairment::default()
+            },
+            Impairment {
+                max_kbps: std::u32::MAX,
+                ..Impairment::default()
+            },
+        ];
+
+        for impairment in &invalid {
+            assert!(impairment.validate().is_err(), "{:?}", impairment);
+        }
+    }
+
+    #[test]
+    fn saturates_netsim_properties() {
+        assert_eq!(saturate(50), 50);
+        assert_eq!(saturate(std::u32::MAX), std::i32::MAX);
+    }
+}
@@ -0,0 +1,506 @@
+// Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
+
+//! Structured breakdowns of sdp payloads, and the differences between an offer and its
+//! answer, for figuring out why a negotiation failed without pasting sdps into other tools.
+
+use serde::Serialize;
+use webrtc_sdp::attribute_type::{SdpAttribute, SdpAttributePayloadType, SdpAttributeType};
+use webrtc_sdp::media_type::{SdpFormatList, SdpMedia};
+use webrtc_sdp::SdpSession;
+
+/// A local or remote description of a session: the raw text, and its breakdown if it
+/// could be parsed
+#[derive(Debug, Serialize)]
+pub struct DescriptionReport {
+    #[serde(rename = "type")]
+    pub sdp_type: String,
+    pub raw: String,
+    pub parsed: Option<SdpSummary>,
+    pub error: Option<String>,
+}
+
+/// The descriptions of a session, as returned by the sdp inspection endpoint
+#[derive(Debug, Serialize)]
+pub struct SessionDescriptions {
+    pub signaling_state: Option<String>,
+    pub local: Option<DescriptionReport>,
+    pub remote: Option<DescriptionReport>,
+    /// present once one description is an offer and the other its answer
+    pub diff: Option<SdpDiff>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SdpSummary {
+    pub session_id: u64,
+    pub session_version: u64,
+    pub groups: Vec<String>,
+    pub ice_ufrag: Option<String>,
+    pub ice_pwd: Option<String>,
+    pub fingerprints: Vec<String>,
+    pub setup: Option<String>,
+    pub media: Vec<MediaSummary>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MediaSummary {
+    pub index: usize,
+    pub mid: Option<String>,
+    pub kind: String,
+    pub port: u32,
+    pub protocol: String,
+    pub direction: String,
+    pub codecs: Vec<CodecSummary>,
+    pub extmaps: Vec<String>,
+    pub candidates: Vec<String>,
+    pub end_of_candidates: bool,
+    pub ice_ufrag: Option<String>,
+    pub ice_pwd: Option<String>,
+    pub fingerprints: Vec<String>,
+    pub setup: Option<String>,
+    pub rtcp_mux: bool,
+    pub bundle_only: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CodecSummary {
+    pub payload_type: u32,
+    pub name: String,
+    pub clock_rate: Option<u32>,
+    pub channels: Option<u32>,
+    pub fmtp: Option<String>,
+    pub rtcp_fb: Vec<String>,
+}
+
+/// The differences between an offer and its answer, per media line
+#[derive(Debug, Serialize)]
+pub struct SdpDiff {
+    pub offer_groups: Vec<String>,
+    pub answer_groups: Vec<String>,
+    pub media: Vec<MediaDiff>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MediaDiff {
+    pub index: usize,
+    pub mid: Option<String>,
+    pub kind: String,
+    /// the answer has no such media line, or set its port to zero
+    pub rejected: bool,
+    pub offer_direction: String,
+    pub answer_direction: Option<String>,
+    /// whether the answer's direction is a valid response to the offer's (RFC 3264, 6.1)
+    pub direction_compatible: bool,
+    pub common_codecs: Vec<String>,
+    pub offered_only_codecs: Vec<String>,
+    pub answered_only_codecs: Vec<String>,
+    pub fmtp_changes: Vec<FmtpChange>,
+    pub offer_setup: Option<String>,
+    pub answer_setup: Option<String>,
+    pub rtcp_mux: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FmtpChange {
+    pub codec: String,
+    pub offer: Option<String>,
+    pub answer: Option<String>,
+}
+
+pub fn summarize(session: &SdpSession) -> SdpSummary {
+    SdpSummary {
+        session_id: session.origin.session_id,
+        session_version: session.origin.session_version,
+        groups: groups(session),
+        ice_ufrag: session_value(session, SdpAttributeType::IceUfrag),
+        ice_pwd: session_value(session, SdpAttributeType::IcePwd),
+        fingerprints: session
+            .attribute
+            .iter()
+            .filter(|a| SdpAttributeType::from(*a) == SdpAttributeType::Fingerprint)
+            .map(value)
+            .collect(),
+        setup: session_value(session, SdpAttributeType::Setup),
+        media: session
+            .media
+            .iter()
+            .enumerate()
+            .map(|(index, m)| summarize_media(session, index, m))
+            .collect(),
+    }
+}
+
+pub fn diff(offer: &SdpSession, answer: &SdpSession) -> SdpDiff {
+    let offer_summary = summarize(offer);
+    let answer_summary = summarize(answer);
+
+    let media = offer_summary
+        .media
+        .iter()
+        .map(|o| {
+            let a = answer_summary
+                .media
+                .iter()
+                .find(|a| o.mid.is_some() && a.mid == o.mid)
+                .or_else(|| answer_summary.media.get(o.index));
+
+            diff_media(o, a)
+        })
+        .collect();
+
+    SdpDiff {
+        offer_groups: offer_summary.groups,
+        answer_groups: answer_summary.groups,
+        media,
+    }
+}
+
+fn diff_media(offer: &MediaSummary, answer: Option<&MediaSummary>) -> MediaDiff {
+    let empty = vec![];
+    let answer_codecs = answer.map(|a| &a.codecs).unwrap_or(&empty);
+
+    let common = offer
+        .codecs
+        .iter()
+        .filter_map(|o| find_codec(answer_codecs, o).map(|a| (o, a)))
+        .collect::<Vec<_>>();
+
+    MediaDiff {
+        index: offer.index,
+        mid: offer.mid.clone(),
+        kind: offer.kind.clone(),
+        rejected: answer.map_or(true, |a| a.port == 0),
+        offer_direction: offer.direction.clone(),
+        answer_direction: answer.map(|a| a.direction.clone()),
+        direction_compatible: answer.map_or(false, |a| {
+            direction_compatible(&offer.direction, &a.direction)
+        }),
+        common_codecs: common.iter().map(|(o, _)| codec_name(o)).collect(),
+        offered_only_codecs: offer
+            .codecs
+            .iter()
+            .filter(|o| find_codec(answer_codecs, o).is_none())
+            .map(codec_name)
+            .collect(),
+        answered_only_codecs: answer_codecs
+            .iter()
+            .filter(|a| find_codec(&offer.codecs, a).is_none())
+            .map(codec_name)
+            .collect(),
+        fmtp_changes: common
+            .iter()
+            .filter(|(o, a)| o.fmtp != a.fmtp)
+            .map(|(o, a)| FmtpChange {
+                codec: codec_name(o),
+                offer: o.fmtp.clone(),
+                answer: a.fmtp.clone(),
+            })
+            .collect(),
+        offer_setup: offer.setup.clone(),
+        answer_setup: answer.and_then(|a| a.setup.clone()),
+        rtcp_mux: offer.rtcp_mux && answer.map_or(false, |a| a.rtcp_mux),
+    }
+}
+
+/// Whether the direction of an answered media line is allowed for the offered one
+pub fn direction_compatible(offer: &str, answer: &str) -> bool {
+    matches!(
+        (offer, answer),
+        (_, "inactive") | ("sendrecv", _) | ("sendonly", "recvonly") | ("recvonly", "sendonly")
+    )
+}
+
+fn summarize_media(session: &SdpSession, index: usize, media: &SdpMedia) -> MediaSummary {
+    let media_value = |t: SdpAttributeType| media.get_attribute(t).map(value);
+    let media_values = |t: SdpAttributeType| {
+        media
+            .get_attributes_of_type(t)
+            .into_iter()
+            .map(value)
+            .collect::<Vec<_>>()
+    };
+
+    MediaSummary {
+        index,
+        mid: media_value(SdpAttributeType::Mid),
+        kind: media.get_type().to_string(),
+        port: media.get_port(),
+        protocol: media.get_proto().to_string(),
+        direction: direction(session, media),
+        codecs: codecs(media),
+        extmaps: media_values(SdpAttributeType::Extmap),
+        candidates: media_values(SdpAttributeType::Candidate),
+        end_of_candidates: media
+            .get_attribute(SdpAttributeType::EndOfCandidates)
+            .is_some(),
+        ice_ufrag: media_value(SdpAttributeType::IceUfrag),
+        ice_pwd: media_value(SdpAttributeType::IcePwd),
+        fingerprints: media_values(SdpAttributeType::Fingerprint),
+        setup: media_value(SdpAttributeType::Setup),
+        rtcp_mux: media.get_attribute(SdpAttributeType::RtcpMux).is_some(),
+        bundle_only: media.get_attribute(SdpAttributeType::BundleOnly).is_some(),
+    }
+}
+
+/// The direction of a media line, falling back to the session level direction and
+/// finally to the sendrecv default
+fn direction(session: &SdpSession, media: &SdpMedia) -> String {
+    let is_direction = |a: &&SdpAttribute| {
+        matches!(
+            a,
+            SdpAttribute::Sendrecv
+                | SdpAttribute::Sendonly
+                | SdpAttribute::Recvonly
+                | SdpAttribute::Inactive
+        )
+    };
+
+    media
+        .get_attributes()
+        .iter()
+        .find(is_direction)
+        .or_else(|| session.attribute.iter().find(is_direction))
+        .map(|a| a.to_string())
+        .unwrap_or_else(|| "sendrecv".to_string())
+}
+
+fn codecs(media: &SdpMedia) -> Vec<CodecSummary> {
+    let payload_types = match media.get_formats() {
+        SdpFormatList::Integers(p) => p.clone(),
+        _ => return vec![],
+    };
+
+    payload_types
+        .iter()
+        .map(|pt| {
+            let rtpmap = media.get_attributes().iter().find_map(|a| match a {
+                SdpAttribute::Rtpmap(r) if u32::from(r.payload_type) == *pt => Some(r),
+                _ => None,
+            });
+            let fmtp = media.get_attributes().iter().find_map(|a| match a {
+                SdpAttribute::Fmtp(f) if u32::from(f.payload_type) == *pt => {
+                    Some(f.parameters.to_string())
+                }
+                _ => None,
+            });
+            let rtcp_fb = media
+                .get_attributes()
+                .iter()
+                .filter_map(|a| match a {
+                    SdpAttribute::Rtcpfb(f) => match f.payload_type {
+                        SdpAttributePayloadType::PayloadType(p) if u32::from(p) == *pt => {
+                            Some(f.to_string())
+                        }
+                        SdpAttributePayloadType::Wildcard => Some(f.to_string()),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect();
+
+            CodecSummary {
+                payload_type: *pt,
+                name: rtpmap
+                    .map(|r| r.codec_name.clone())
+                    .unwrap_or_else(|| "static".to_string()),
+                clock_rate: rtpmap.map(|r| r.frequency),
+                channels: rtpmap.and_then(|r| r.channels),
+                fmtp: fmtp.filter(|f| !f.is_empty()),
+                rtcp_fb,
+            }
+        })
+        .collect()
+}
+
+fn find_codec<'a>(codecs: &'a [CodecSummary], codec: &CodecSummary) -> Option<&'a CodecSummary> {
+    codecs
+        .iter()
+        .find(|c| c.payload_type == codec.payload_type && c.name.eq_ignore_ascii_case(&codec.name))
+}
+
+fn codec_name(codec: &CodecSummary) -> String {
+    match codec.clock_rate {
+        Some(rate) => format!("{} {}/{}", codec.payload_type, codec.name, rate),
+        None => format!("{} {}", codec.payload_type, codec.name),
+    }
+}
+
+fn groups(session: &SdpSession) -> Vec<String> {
+    session
+        .attribute
+        .iter()
+        .filter_map(|a| match a {
+            SdpAttribute::Group(g) => Some(g.to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn session_value(session: &SdpSession, t: SdpAttributeType) -> Option<String> {
+    session.get_attribute(t).map(value)
+}
+
+/// The value of an attribute, i.e. everything after the `a=<name>:`
+fn value(attribute: &SdpAttribute) -> String {
+    let line = attribute.to_string();
+    match line.find(':') {
+        Some(i) => line[i + 1..].to_string(),
+        None => line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER: &str = "v=0\r
+o=- 1 2 IN IP4 127.0.0.1\r
+s=-\r
+t=0 0\r
+a=group:BUNDLE 0 1\r
+m=audio 9 UDP/TLS/RTP/SAVPF 111 0\r
+c=IN IP4 0.0.0.0\r
+a=mid:0\r
+a=sendrecv\r
+a=rtcp-mux\r
+a=setup:actpass\r
+a=rtpmap:111 opus/48000/2\r
+a=fmtp:111 minptime=10;useinbandfec=1\r
+a=rtcp-fb:* nack\r
+m=video 9 UDP/TLS/RTP/SAVPF 96 97\r
+c=IN IP4 0.0.0.0\r
+a=mid:1\r
+a=sendonly\r
+a=rtcp-mux\r
+a=setup:actpass\r
+a=rtpmap:96 VP8/90000\r
+a=rtpmap:97 H264/90000\r
+a=fmtp:97 profile-level-id=42e01f\r
+";
+
+    const ANSWER: &str = "v=0\r
+o=- 3 4 IN IP4 127.0.0.1\r
+s=-\r
+t=0 0\r
+a=group:BUNDLE 0\r
+m=audio 9 UDP/TLS/RTP/SAVPF 111 8\r
+c=IN IP4 0.0.0.0\r
+a=mid:0\r
+a=recvonly\r
+a=rtcp-mux\r
+a=setup:active\r
+a=rtpmap:111 opus/48000/2\r
+a=fmtp:111 minptime=10\r
+a=rtpmap:8 PCMA/8000\r
+m=video 0 UDP/TLS/RTP/SAVPF 96\r
+c=IN IP4 0.0.0.0\r
+a=mid:1\r
+a=sendonly\r
+a=rtpmap:96 VP8/90000\r
+";
+
+    fn parse(sdp: &str) -> SdpSession {
+        webrtc_sdp::parse_sdp(sdp, false).unwrap()
+    }
+
+    #[test]
+    fn summarizes_media_lines() {
+        let summary = summarize(&parse(OFFER));
+
+        assert_eq!(summary.session_version, 2);
+        assert_eq!(summary.groups, vec!["BUNDLE 0 1"]);
+        assert_eq!(summary.media.len(), 2);
+
+        let audio = &summary.media[0];
+        assert_eq!(audio.mid.as_deref(), Some("0"));
+        assert_eq!(audio.kind, "audio");
+        assert_eq!(audio.direction, "sendrecv");
+        assert!(audio.rtcp_mux);
+        assert_eq!(audio.setup.as_deref(), Some("actpass"));
+
+        let names = audio.codecs.iter().map(codec_name).collect::<Vec<_>>();
+        assert_eq!(names, vec!["111 opus/48000", "0 static"]);
+        // wildcard feedback applies to every codec
+        assert!(audio.codecs.iter().all(|c| c.rtcp_fb.len() == 1));
+        assert!(audio.codecs[0]
+            .fmtp
+            .as_deref()
+            .unwrap()
+            .contains("useinbandfec"));
+    }
+
+    #[test]
+    fn direction_falls_back_to_the_session() {
+        let sdp = OFFER
+            .replace("a=sendrecv\r\n", "")
+            .replace("t=0 0\r\n", "t=0 0\r\na=recvonly\r\n");
+        let summary = summarize(&parse(&sdp));
+
+        assert_eq!(summary.media[0].direction, "recvonly");
+        assert_eq!(summary.media[1].direction, "sendonly");
+    }
+
+    #[test]
+    fn diff_compares_codecs_and_directions() {
+        let diff = diff(&parse(OFFER), &parse(ANSWER));
+
+        assert_eq!(diff.offer_groups, vec!["BUNDLE 0 1"]);
+        assert_eq!(diff.answer_groups, vec!["BUNDLE 0"]);
+
+        let audio = &diff.media[0];
+        assert!(!audio.rejected);
+        assert_eq!(audio.answer_direction.as_deref(), Some("recvonly"));
+        assert!(audio.direction_compatible);
+        assert_eq!(audio.common_codecs, vec!["111 opus/48000"]);
+        assert_eq!(audio.offered_only_codecs, vec!["0 static"]);
+        assert_eq!(audio.answered_only_codecs, vec!["8 PCMA/8000"]);
+        assert_eq!(audio.fmtp_changes.len(), 1);
+        assert_eq!(audio.fmtp_changes[0].codec, "111 opus/48000");
+        assert_eq!(audio.offer_setup.as_deref(), Some("actpass"));
+        assert_eq!(audio.answer_setup.as_deref(), Some("active"));
+        assert!(audio.rtcp_mux);
+
+        let video = &diff.media[1];
+        assert!(video.rejected);
+        assert!(!video.direction_compatible);
+        assert_eq!(video.offered_only_codecs, vec!["97 H264/90000"]);
+        assert!(!video.rtcp_mux);
+    }
+
+    #[test]
+    fn diff_marks_missing_media_lines_as_rejected() {
+        let answer = ANSWER.split("m=video").next().unwrap();
+        let diff = diff(&parse(OFFER), &parse(answer));
+
+        let video = &diff.media[1];
+        assert!(video.rejected);
+        assert_eq!(video.answer_direction, None);
+        assert!(!video.direction_compatible);
+        assert_eq!(video.offered_only_codecs.len(), 2);
+    }
+
+    #[test]
+    fn answered_directions_follow_rfc_3264() {
+        let directions = ["sendrecv", "sendonly", "recvonly", "inactive"];
+        let allowed = [
+            (
+                "sendrecv",
+                vec!["sendrecv", "sendonly", "recvonly", "inactive"],
+            ),
+            ("sendonly", vec!["recvonly", "inactive"]),
+            ("recvonly", vec!["sendonly", "inactive"]),
+            ("inactive", vec!["inactive"]),
+        ];
+
+        for (offer, answers) in &allowed {
+            for answer in &directions {
+                assert_eq!(
+                    direction_compatible(offer, answer),
+                    answers.contains(answer),
+                    "{} answered with {}",
+                    offer,
+                    answer
+                );
+            }
+        }
+    }
+}
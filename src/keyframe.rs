@@ -0,0 +1,130 @@
+// Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
+
+//! Keyframe requests in both directions. A `GstForceKeyUnit` event sent upstream towards
+//! an encoder makes it produce a keyframe. Rtpbin sends one when the remote party asks with
+//! an rtcp PLI or FIR, and turns one sent upstream from an incoming stream into a PLI.
+
+use std::sync::Arc;
+
+use gst::prelude::*;
+use gstreamer as gst;
+
+use super::congestion::ENCODER_PREFIX;
+use super::events::EventBus;
+use super::gstlib::*;
+
+const FORCE_KEY_UNIT: &str = "GstForceKeyUnit";
+
+fn force_key_unit_event() -> gst::Event {
+    let structure = gst::Structure::builder(FORCE_KEY_UNIT)
+        .field("all-headers", &true)
+        .field("count", &0u32)
+        .build();
+
+    gst::Event::new_custom_upstream(structure).build()
+}
+
+fn is_force_key_unit(event: &gst::EventRef) -> bool {
+    event.get_type() == gst::EventType::CustomUpstream
+        && event
+            .get_structure()
+            .map_or(false, |s| s.get_name() == FORCE_KEY_UNIT)
+}
+
+/// Makes every video encoder of the pipeline produce a keyframe. Returns the number of
+/// encoders asked.
+pub fn force_key_unit(pipeline: &gst::Pipeline) -> usize {
+    pipeline
+        .iterate_recurse()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.get_name().starts_with(ENCODER_PREFIX))
+        .filter_map(|e| e.get_static_pad("src"))
+        .filter(|pad| pad.send_event(force_key_unit_event()))
+        .count()
+}
+
+/// Asks the remote party for a keyframe of every incoming video stream; rtpbin sends it
+/// as a PLI. Returns the number of streams asked.
+pub fn request_key_unit(webrtcbin: &gst::Element) -> usize {
+    webrtcbin
+        .get_src_pads()
+        .into_iter()
+        .filter(|pad| media_kind(pad).as_deref() == Some("video"))
+        .filter(request_key_unit_on)
+        .count()
+}
+
+/// Asks the remote party for a keyframe of the stream on an incoming pad of webrtcbin
+fn request_key_unit_on(pad: &gst::Pad) -> bool {
+    pad.send_event(force_key_unit_event())
+}
+
+fn media_kind(pad: &gst::Pad) -> Option<String> {
+    let caps = pad.get_current_caps()?;
+    let structure = caps.get_structure(0)?;
+    structure.get::<String>("media").ok().flatten()
+}
+
+/// Publishes a `keyframe-requested` event, carrying the pad's name, whenever a keyframe
+/// of the media linked to one of webrtcbin's sink pads is requested by the remote party
+pub fn watch_requests(sinkpad: &gst::Pad, events: Arc<EventBus>) {
+    let name = sinkpad.get_name().to_string();
+
+    sinkpad.add_probe(gst::PadProbeType::EVENT_UPSTREAM, move |_pad, info| {
+        if let Some(gst::PadProbeData::Event(ref event)) = info.data {
+            if is_force_key_unit(event) {
+                info!("Keyframe requested on {}", name);
+                events.announce("keyframe-requested", &name);
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// Makes the video encoders produce a keyframe as soon as ice connects; the keyframes
+/// encoded before could not be sent, and the remote party would wait for the next one
+pub fn force_on_connect(pipeline: &gst::Pipeline, webrtcbin: &gst::Element) {
+    let pipeline = pipeline.downgrade();
+
+    webrtcbin.connect_notify(Some("ice-connection-state"), move |webrtcbin, _| {
+        let state = get_property_nick(webrtcbin, "ice-connection-state").unwrap_or_default();
+        if state != "connected" {
+            return;
+        }
+
+        if let Some(pipeline) = pipeline.upgrade() {
+            let forced = force_key_unit(&pipeline);
+            debug!("Ice connected; forced a keyframe on {} encoders", forced);
+        }
+    });
+}
+
+/// Makes the depayloaders of an incoming stream ask for a keyframe when packets are lost,
+/// and drop the stream until it arrives, where they support it
+pub fn request_on_loss(decodebin: &gst::Element) {
+    decodebin
+        .connect("deep-element-added", false, |values| {
+            let element = values[2]
+                .get::<gst::Element>()
+                .expect("Invalid argument")
+                .expect("Should never be null.");
+
+            for property in &["request-keyframe", "wait-for-keyframe"] {
+                let property = *property;
+                if element.find_property(property).is_some() {
+                    if let Err(e) = element.set_property(property, &true) {
+                        warn!(
+                            "Could not set {} on {}: {}",
+                            property,
+                            element.get_name(),
+                            e
+                        );
+                    }
+                }
+            }
+
+            None
+        })
+        .unwrap();
+}
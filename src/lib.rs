@@ -0,0 +1,30 @@
+// Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
+
+//! The signaling server's api and its webrtcbin sessions, shared by the server and the
+//! headless client binaries.
+
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate log;
+
+pub mod api;
+mod candidate;
+pub mod client;
+mod congestion;
+pub mod domain;
+pub mod events;
+pub mod extmap;
+mod gstlib;
+pub mod impairment;
+pub mod inspect;
+pub mod keyframe;
+mod mdns;
+mod metrics;
+mod moz_ice;
+pub mod munging;
+mod sdp_text;
+pub mod simulcast;
+pub mod snapshot;
+pub mod validation;
+mod voice;
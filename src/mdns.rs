@@ -0,0 +1,444 @@
+// Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
+
+//! Minimal multicast DNS (RFC 6762) support for ice candidates.
+//!
+//! Browsers hide the local addresses of their host candidates behind random
+//! `<uuid>.local` names, which libnice cannot resolve. This module resolves such names
+//! with one-shot queries, and can publish the media server's own host candidates the
+//! same way, answering queries for the published names on the mdns multicast group.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+const MDNS_ADDRESS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_MASK: u16 = 0x7fff;
+const UNICAST_RESPONSE: u16 = 0x8000;
+const CACHE_FLUSH: u16 = 0x8000;
+const FLAGS_RESPONSE: u16 = 0x8400;
+const RECORD_TTL: u32 = 120;
+
+lazy_static! {
+    /// Names published for local host candidates, and the addresses they resolve to
+    static ref PUBLISHED: Mutex<HashMap<String, Ipv4Addr>> = Mutex::new(HashMap::new());
+
+    /// Outcome of starting the responder, kept so that every later publish reports it
+    static ref RESPONDER: Mutex<Option<std::result::Result<(), String>>> = Mutex::new(None);
+}
+
+pub fn is_mdns_hostname(host: &str) -> bool {
+    host.to_lowercase().ends_with(".local")
+}
+
+/// Resolves a `.local` hostname to an ipv4 address using a one-shot multicast query,
+/// waiting at most `timeout` for an answer
+pub fn resolve(hostname: &str, timeout: Duration) -> Result<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    let id = rand::random::<u16>();
+
+    let mut query = vec![];
+    write_header(&mut query, id, 0, 1, 0);
+    write_name(&mut query, hostname);
+    write_u16(&mut query, TYPE_A);
+    write_u16(&mut query, CLASS_IN | UNICAST_RESPONSE);
+
+    socket.send_to(&query, SocketAddrV4::new(MDNS_ADDRESS, MDNS_PORT))?;
+
+    let deadline = Instant::now() + timeout;
+    let mut buffer = [0u8; 1500];
+    loop {
+        let remaining = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|d| *d > Duration::from_millis(0))
+            .ok_or_else(|| anyhow!("Timed out resolving {}", hostname))?;
+        socket.set_read_timeout(Some(remaining))?;
+
+        let (len, _) = match socket.recv_from(&mut buffer) {
+            Ok(r) => r,
+            Err(_) => return Err(anyhow!("Timed out resolving {}", hostname)),
+        };
+
+        if let Some(address) = find_answer(&buffer[..len], hostname) {
+            debug!("Resolved {} to {}", hostname, address);
+            return Ok(IpAddr::V4(address));
+        }
+    }
+}
+
+/// Publishes a random `<uuid>.local` name for the given address and returns it. The
+/// responder answering queries for published names is started on first use.
+pub fn publish(address: Ipv4Addr) -> Result<String> {
+    let mut published = PUBLISHED.lock().unwrap();
+
+    if let Some((name, _)) = published.iter().find(|(_, a)| **a == address) {
+        return Ok(name.clone());
+    }
+
+    RESPONDER
+        .lock()
+        .unwrap()
+        .get_or_insert_with(|| start_responder().map_err(|e| e.to_string()))
+        .clone()
+        .map_err(|e| anyhow!("Could not start the mdns responder: {}", e))?;
+
+    let name = format!("{}.local", random_uuid());
+    info!("Publishing {} as {}", address, name);
+    published.insert(name.clone(), address);
+
+    Ok(name)
+}
+
+fn random_uuid() -> String {
+    let mut bytes = rand::random::<[u8; 16]>();
+    // version 4, variant 1
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex = bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+fn start_responder() -> Result<()> {
+    let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&SockAddr::from(SocketAddrV4::new(
+        Ipv4Addr::UNSPECIFIED,
+        MDNS_PORT,
+    )))?;
+    socket.join_multicast_v4(&MDNS_ADDRESS, &Ipv4Addr::UNSPECIFIED)?;
+    let socket = socket.into_udp_socket();
+
+    thread::Builder::new()
+        .name("mdns-responder".to_string())
+        .spawn(move || {
+            let mut buffer = [0u8; 1500];
+            loop {
+                let (len, source) = match socket.recv_from(&mut buffer) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        warn!("Mdns responder stopped: {}", e);
+                        return;
+                    }
+                };
+
+                if let Some((response, destination)) = answer_query(&buffer[..len], source) {
+                    if let Err(e) = socket.send_to(&response, destination) {
+                        debug!("Could not send mdns response: {}", e);
+                    }
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Builds the response to a query for one of the published names, along with the
+/// address it has to be sent to
+fn answer_query(packet: &[u8], source: SocketAddr) -> Option<(Vec<u8>, SocketAddr)> {
+    let id = read_u16(packet, 0)?;
+    let flags = read_u16(packet, 2)?;
+    let questions = read_u16(packet, 4)?;
+
+    // ignore responses
+    if flags & 0x8000 != 0 {
+        return None;
+    }
+
+    let published = PUBLISHED.lock().unwrap();
+    let mut offset = 12;
+    for _ in 0..questions {
+        let (name, next) = read_name(packet, offset)?;
+        let qtype = read_u16(packet, next)?;
+        let qclass = read_u16(packet, next + 2)?;
+        offset = next + 4;
+
+        if qtype != TYPE_A && qtype != TYPE_ANY {
+            continue;
+        }
+
+        let address = match published.get(&name.to_lowercase()) {
+            Some(a) => *a,
+            None => continue,
+        };
+
+        // one-shot queries from a port other than 5353 are answered directly, and must
+        // repeat the id and question (RFC 6762, section 6.7)
+        let legacy = source.port() != MDNS_PORT;
+        let unicast = legacy || qclass & UNICAST_RESPONSE != 0;
+
+        let mut response = vec![];
+        if legacy {
+            write_header(&mut response, id, FLAGS_RESPONSE, 1, 1);
+            write_name(&mut response, &name);
+            write_u16(&mut response, TYPE_A);
+            write_u16(&mut response, CLASS_IN);
+        } else {
+            write_header(&mut response, 0, FLAGS_RESPONSE, 0, 1);
+        }
+        write_name(&mut response, &name);
+        write_u16(&mut response, TYPE_A);
+        write_u16(&mut response, CLASS_IN | CACHE_FLUSH);
+        response.extend_from_slice(&RECORD_TTL.to_be_bytes());
+        write_u16(&mut response, 4);
+        response.extend_from_slice(&address.octets());
+
+        let destination = if unicast {
+            source
+        } else {
+            SocketAddr::V4(SocketAddrV4::new(MDNS_ADDRESS, MDNS_PORT))
+        };
+
+        return Some((response, destination));
+    }
+
+    None
+}
+
+/// Looks for an A record for the given name in the answers of a response
+fn find_answer(packet: &[u8], hostname: &str) -> Option<Ipv4Addr> {
+    let flags = read_u16(packet, 2)?;
+    if flags & 0x8000 == 0 {
+        return None;
+    }
+
+    let questions = read_u16(packet, 4)?;
+    let answers = read_u16(packet, 6)?;
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        let (_, next) = read_name(packet, offset)?;
+        offset = next + 4;
+    }
+
+    for _ in 0..answers {
+        let (name, next) = read_name(packet, offset)?;
+        let rtype = read_u16(packet, next)?;
+        let rclass = read_u16(packet, next + 2)?;
+        let length = read_u16(packet, next + 8)? as usize;
+        let data = next + 10;
+        offset = data + length;
+
+        if rtype == TYPE_A
+            && rclass & CLASS_MASK == CLASS_IN
+            && length == 4
+            && name.eq_ignore_ascii_case(hostname)
+        {
+            let octets = packet.get(data..data + 4)?;
+            return Some(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]));
+        }
+    }
+
+    None
+}
+
+fn write_header(packet: &mut Vec<u8>, id: u16, flags: u16, questions: u16, answers: u16) {
+    write_u16(packet, id);
+    write_u16(packet, flags);
+    write_u16(packet, questions);
+    write_u16(packet, answers);
+    write_u16(packet, 0);
+    write_u16(packet, 0);
+}
+
+fn write_name(packet: &mut Vec<u8>, name: &str) {
+    name.trim_end_matches('.').split('.').for_each(|label| {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    });
+    packet.push(0);
+}
+
+fn write_u16(packet: &mut Vec<u8>, value: u16) {
+    packet.extend_from_slice(&value.to_be_bytes());
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    packet
+        .get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+/// Reads a possibly compressed name, returning it along with the offset just past it
+fn read_name(packet: &[u8], offset: usize) -> Option<(String, usize)> {
+    let mut labels = vec![];
+    let mut position = offset;
+    let mut end = None;
+
+    // bound the number of pointers followed, to guard against loops
+    for _ in 0..128 {
+        let length = *packet.get(position)? as usize;
+
+        if length == 0 {
+            let next = end.unwrap_or(position + 1);
+            return Some((labels.join("."), next));
+        }
+
+        if length & 0xc0 == 0xc0 {
+            let pointer = (read_u16(packet, position)? & 0x3fff) as usize;
+            end.get_or_insert(position + 2);
+            position = pointer;
+            continue;
+        }
+
+        let label = packet.get(position + 1..position + 1 + length)?;
+        labels.push(String::from_utf8_lossy(label).to_string());
+        position += 1 + length;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(id: u16, name: &str, qclass: u16) -> Vec<u8> {
+        let mut packet = vec![];
+        write_header(&mut packet, id, 0, 1, 0);
+        write_name(&mut packet, name);
+        write_u16(&mut packet, TYPE_A);
+        write_u16(&mut packet, qclass);
+        packet
+    }
+
+    fn publish_for_test(address: Ipv4Addr) -> String {
+        let name = format!("{}.local", random_uuid());
+        PUBLISHED.lock().unwrap().insert(name.clone(), address);
+        name
+    }
+
+    #[test]
+    fn names_round_trip() {
+        let mut packet = vec![];
+        write_name(&mut packet, "a1b2.local.");
+
+        assert_eq!(packet, b"\x04a1b2\x05local\x00");
+        assert_eq!(
+            read_name(&packet, 0),
+            Some(("a1b2.local".to_string(), packet.len()))
+        );
+    }
+
+    #[test]
+    fn reads_compressed_names() {
+        let mut packet = vec![];
+        write_name(&mut packet, "host.local");
+        let second = packet.len();
+        packet.extend_from_slice(b"\x03www\xc0\x05");
+
+        assert_eq!(
+            read_name(&packet, second),
+            Some(("www.local".to_string(), second + 6))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_names() {
+        // a pointer to itself
+        assert_eq!(read_name(b"\xc0\x00", 0), None);
+        // a label running past the end of the packet
+        assert_eq!(read_name(b"\x05ab", 0), None);
+        assert_eq!(read_u16(b"\x01", 0), None);
+    }
+
+    #[test]
+    fn uuids_are_version_4() {
+        let uuid = random_uuid();
+        let groups = uuid.split('-').map(str::len).collect::<Vec<_>>();
+
+        assert_eq!(groups, vec![8, 4, 4, 4, 12]);
+        assert_eq!(&uuid[14..15], "4");
+        assert!("89ab".contains(&uuid[19..20]));
+    }
+
+    #[test]
+    fn answers_one_shot_queries_directly() {
+        let address = Ipv4Addr::new(192, 168, 1, 20);
+        let name = publish_for_test(address);
+        let source = "192.168.1.30:40000".parse().unwrap();
+
+        let (response, destination) = answer_query(&query(77, &name, CLASS_IN), source).unwrap();
+
+        assert_eq!(destination, source);
+        assert_eq!(read_u16(&response, 0), Some(77));
+        assert_eq!(read_u16(&response, 4), Some(1));
+        assert_eq!(find_answer(&response, &name), Some(address));
+    }
+
+    #[test]
+    fn answers_multicast_queries_on_the_group() {
+        let address = Ipv4Addr::new(10, 0, 0, 5);
+        let name = publish_for_test(address);
+        let source = "10.0.0.6:5353".parse().unwrap();
+
+        let (response, destination) =
+            answer_query(&query(0, &name.to_uppercase(), CLASS_IN), source).unwrap();
+        assert_eq!(
+            destination,
+            SocketAddr::V4(SocketAddrV4::new(MDNS_ADDRESS, MDNS_PORT))
+        );
+        assert_eq!(read_u16(&response, 4), Some(0));
+        assert_eq!(find_answer(&response, &name), Some(address));
+
+        let (_, destination) =
+            answer_query(&query(0, &name, CLASS_IN | UNICAST_RESPONSE), source).unwrap();
+        assert_eq!(destination, source);
+    }
+
+    #[test]
+    fn ignores_unknown_names_and_responses() {
+        let name = publish_for_test(Ipv4Addr::new(10, 0, 0, 7));
+        let source = "10.0.0.8:5353".parse().unwrap();
+
+        assert!(answer_query(&query(0, "unknown.local", CLASS_IN), source).is_none());
+
+        let mut response = query(0, &name, CLASS_IN);
+        response[2] = 0x84;
+        assert!(answer_query(&response, source).is_none());
+        assert!(answer_query(&response[..20], source).is_none());
+    }
+
+    #[test]
+    fn finds_answers_for_the_queried_name_only() {
+        let mut response = vec![];
+        write_header(&mut response, 0, FLAGS_RESPONSE, 0, 1);
+        write_name(&mut response, "other.local");
+        write_u16(&mut response, TYPE_A);
+        write_u16(&mut response, CLASS_IN | CACHE_FLUSH);
+        response.extend_from_slice(&RECORD_TTL.to_be_bytes());
+        write_u16(&mut response, 4);
+        response.extend_from_slice(&[10, 0, 0, 9]);
+
+        assert_eq!(
+            find_answer(&response, "other.local"),
+            Some(Ipv4Addr::new(10, 0, 0, 9))
+        );
+        assert_eq!(find_answer(&response, "host.local"), None);
+        // queries are not answers
+        assert_eq!(
+            find_answer(&query(0, "other.local", CLASS_IN), "other.local"),
+            None
+        );
+    }
+}
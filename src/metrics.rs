@@ -0,0 +1,259 @@
+// Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Upper bounds, in seconds, of the time-to-connected histogram buckets
+const CONNECT_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+lazy_static! {
+    /// Process wide metrics; updated from the http handlers as well as from webrtcbin
+    /// signal callbacks, which run on gstreamer's threads
+    pub static ref METRICS: Metrics = Metrics::new();
+}
+
+/// Simple cumulative histogram, rendered in the prometheus text format
+#[derive(Debug)]
+struct Histogram {
+    buckets: Vec<(f64, u64)>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &[f64]) -> Self {
+        Histogram {
+            buckets: bounds.iter().map(|b| (*b, 0)).collect(),
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        self.buckets
+            .iter_mut()
+            .filter(|(bound, _)| value <= *bound)
+            .for_each(|(_, count)| *count += 1);
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// The last byte counters read from a session's webrtcbin, used to turn
+/// the cumulative counters into a bitrate between two scrapes
+#[derive(Debug, Clone)]
+struct BitrateSample {
+    at: Instant,
+    bytes_sent: u64,
+    bytes_received: u64,
+    send_bitrate: f64,
+    receive_bitrate: f64,
+}
+
+pub struct Metrics {
+    active_sessions: AtomicI64,
+    offers_created: AtomicU64,
+    answers_accepted: AtomicU64,
+    answers_rejected: AtomicU64,
+    ice_connected: AtomicU64,
+    ice_reconnected: AtomicU64,
+    ice_failed: AtomicU64,
+    time_to_connected: Mutex<Histogram>,
+    session_bitrates: Mutex<HashMap<u64, BitrateSample>>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Metrics {
+            active_sessions: AtomicI64::new(0),
+            offers_created: AtomicU64::new(0),
+            answers_accepted: AtomicU64::new(0),
+            answers_rejected: AtomicU64::new(0),
+            ice_connected: AtomicU64::new(0),
+            ice_reconnected: AtomicU64::new(0),
+            ice_failed: AtomicU64::new(0),
+            time_to_connected: Mutex::new(Histogram::new(&CONNECT_BUCKETS)),
+            session_bitrates: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn session_started(&self) {
+        self.active_sessions.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn session_ended(&self, session_id: u64) {
+        self.active_sessions.fetch_sub(1, Ordering::SeqCst);
+        self.session_bitrates.lock().unwrap().remove(&session_id);
+    }
+
+    pub fn offer_created(&self) {
+        self.offers_created.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn answer_accepted(&self) {
+        self.answers_accepted.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn answer_rejected(&self) {
+        self.answers_rejected.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Records the first time a session's ice connection state reached connected, along with
+    /// how long that took since the session was created
+    pub fn ice_connected(&self, elapsed: Duration) {
+        self.ice_connected.fetch_add(1, Ordering::SeqCst);
+        self.time_to_connected
+            .lock()
+            .unwrap()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Records a session connecting again, after an ice restart or a disconnection
+    pub fn ice_reconnected(&self) {
+        self.ice_reconnected.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn ice_failed(&self) {
+        self.ice_failed.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Records the cumulative byte counters of a session, computing the bitrate
+    /// since the previous sample for that session
+    pub fn record_session_bytes(&self, session_id: u64, bytes_sent: u64, bytes_received: u64) {
+        let now = Instant::now();
+        let mut bitrates = self.session_bitrates.lock().unwrap();
+
+        let (send_bitrate, receive_bitrate) = match bitrates.get(&session_id) {
+            Some(previous) => {
+                let elapsed = now.duration_since(previous.at).as_secs_f64();
+                if elapsed > 0.0 {
+                    (
+                        bytes_sent.saturating_sub(previous.bytes_sent) as f64 * 8.0 / elapsed,
+                        bytes_received.saturating_sub(previous.bytes_received) as f64 * 8.0
+                            / elapsed,
+                    )
+                } else {
+                    (previous.send_bitrate, previous.receive_bitrate)
+                }
+            }
+            None => (0.0, 0.0),
+        };
+
+        bitrates.insert(
+            session_id,
+            BitrateSample {
+                at: now,
+                bytes_sent,
+                bytes_received,
+                send_bitrate,
+                receive_bitrate,
+            },
+        );
+    }
+
+    /// Renders all metrics in the prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        write_metric(
+            &mut out,
+            "webrtc_active_sessions",
+            "gauge",
+            "Number of webrtcbin pipelines currently alive.",
+            self.active_sessions.load(Ordering::SeqCst) as f64,
+        );
+        write_metric(
+            &mut out,
+            "webrtc_offers_created_total",
+            "counter",
+            "Number of sdp offers created by webrtcbin.",
+            self.offers_created.load(Ordering::SeqCst) as f64,
+        );
+        write_metric(
+            &mut out,
+            "webrtc_answers_accepted_total",
+            "counter",
+            "Number of sdp answers applied to webrtcbin.",
+            self.answers_accepted.load(Ordering::SeqCst) as f64,
+        );
+        write_metric(
+            &mut out,
+            "webrtc_answers_rejected_total",
+            "counter",
+            "Number of sdp answers that could not be applied.",
+            self.answers_rejected.load(Ordering::SeqCst) as f64,
+        );
+        write_metric(
+            &mut out,
+            "webrtc_ice_connected_total",
+            "counter",
+            "Number of sessions whose ice connection state reached connected.",
+            self.ice_connected.load(Ordering::SeqCst) as f64,
+        );
+        write_metric(
+            &mut out,
+            "webrtc_ice_reconnected_total",
+            "counter",
+            "Number of times a connected session's ice connection state reached connected again.",
+            self.ice_reconnected.load(Ordering::SeqCst) as f64,
+        );
+        write_metric(
+            &mut out,
+            "webrtc_ice_failed_total",
+            "counter",
+            "Number of sessions whose ice connection state reached failed.",
+            self.ice_failed.load(Ordering::SeqCst) as f64,
+        );
+
+        {
+            let histogram = self.time_to_connected.lock().unwrap();
+            let name = "webrtc_time_to_connected_seconds";
+            let _ = writeln!(
+                out,
+                "# HELP {} Time from pipeline creation until ice connected.",
+                name
+            );
+            let _ = writeln!(out, "# TYPE {} histogram", name);
+            for (bound, count) in histogram.buckets.iter() {
+                let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+            }
+            let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count);
+            let _ = writeln!(out, "{}_sum {}", name, histogram.sum);
+            let _ = writeln!(out, "{}_count {}", name, histogram.count);
+        }
+
+        {
+            let bitrates = self.session_bitrates.lock().unwrap();
+            let name = "webrtc_session_bitrate_bps";
+            let _ = writeln!(
+                out,
+                "# HELP {} Media bitrate per session, measured between scrapes.",
+                name
+            );
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            for (session_id, sample) in bitrates.iter() {
+                let _ = writeln!(
+                    out,
+                    "{}{{session=\"{}\",direction=\"send\"}} {}",
+                    name, session_id, sample.send_bitrate
+                );
+                let _ = writeln!(
+                    out,
+                    "{}{{session=\"{}\",direction=\"receive\"}} {}",
+                    name, session_id, sample.receive_bitrate
+                );
+            }
+        }
+
+        out
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, metric_type: &str, help: &str, value: f64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, metric_type);
+    let _ = writeln!(out, "{} {}", name, value);
+}
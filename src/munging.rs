@@ -0,0 +1,570 @@
+// Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
+
+//! Sdp munging: transforms applied to the parsed sdp before it is sent to the remote party,
+//! or after it is received from it, to reproduce the quirks of other endpoints.
+//!
+//! Transforms are configured per session as a comma separated list, e.g.
+//! `send.bandwidth:video:AS:500,recv.strip-codec:H264,remove-extmap:urn:ietf:params:rtp-hdrext:sdes:mid`.
+//! Each transform is optionally prefixed by the stage it applies to, `send` or `recv`, and
+//! applies to both if there is no prefix. The last argument of a transform takes the rest
+//! of the transform, so it may contain colons.
+//!
+//! * `bandwidth:<audio|video|*>:<AS|TIAS>:<value>` replaces the b= line of that type
+//! * `strip-codec:<name>` removes a codec, along with any rtx payload type for it
+//! * `fmtp:<name>:<params>` sets fmtp parameters of a codec, e.g. `fmtp:VP8:max-fr=15`
+//! * `setup:<active|passive|actpass|holdconn>` rewrites `a=setup`
+//! * `remove-extmap:<uri|*>` removes header extensions with that uri, or all of them
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+use serde::{Deserialize, Deserializer};
+use webrtc_sdp::attribute_type::{SdpAttribute, SdpAttributeSetup};
+use webrtc_sdp::media_type::{SdpFormatList, SdpMedia, SdpMediaLine};
+use webrtc_sdp::{SdpBandwidth, SdpSession};
+
+const SEPARATOR: char = ',';
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Send,
+    Receive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BandwidthType {
+    As,
+    Tias,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transform {
+    Bandwidth {
+        media: Option<String>,
+        bandwidth_type: BandwidthType,
+        value: u32,
+    },
+    StripCodec(String),
+    Fmtp {
+        codec: String,
+        parameters: String,
+    },
+    Setup(String),
+    RemoveExtmap(Option<String>),
+}
+
+/// A transform and the stages it applies to
+#[derive(Debug, Clone, PartialEq)]
+pub struct SdpTransform {
+    pub stages: Vec<Stage>,
+    pub transform: Transform,
+}
+
+impl FromStr for SdpTransform {
+    type Err = Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let spec = spec.trim();
+        let (stages, rest) = if spec.starts_with("send.") {
+            (vec![Stage::Send], &spec["send.".len()..])
+        } else if spec.starts_with("recv.") {
+            (vec![Stage::Receive], &spec["recv.".len()..])
+        } else {
+            (vec![Stage::Send, Stage::Receive], spec)
+        };
+
+        let mut parts = rest.splitn(2, ':');
+        let name = parts.next().unwrap_or_default();
+        let arguments = parts.next().unwrap_or_default();
+        let argument = |n: usize, i: usize| {
+            arguments
+                .splitn(n, ':')
+                .nth(i)
+                .filter(|a| !a.is_empty())
+                .ok_or_else(|| anyhow!("Missing argument {} of sdp transform '{}'", i + 1, spec))
+        };
+
+        let transform = match name {
+            "bandwidth" => Transform::Bandwidth {
+                media: Some(argument(3, 0)?)
+                    .filter(|m| *m != "*")
+                    .map(str::to_lowercase),
+                bandwidth_type: match argument(3, 1)?.to_uppercase().as_ref() {
+                    "AS" => BandwidthType::As,
+                    "TIAS" => BandwidthType::Tias,
+                    t => return Err(anyhow!("Unknown bandwidth type '{}' in '{}'", t, spec)),
+                },
+                value: argument(3, 2)?
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid bandwidth in sdp transform '{}'", spec))?,
+            },
+            "strip-codec" => Transform::StripCodec(argument(1, 0)?.to_string()),
+            "fmtp" => Transform::Fmtp {
+                codec: argument(2, 0)?.to_string(),
+                parameters: argument(2, 1)?.to_string(),
+            },
+            "setup" => match argument(1, 0)? {
+                role @ "active" | role @ "passive" | role @ "actpass" | role @ "holdconn" => {
+                    Transform::Setup(role.to_string())
+                }
+                role => return Err(anyhow!("Unknown setup role '{}' in '{}'", role, spec)),
+            },
+            "remove-extmap" => Transform::RemoveExtmap(
+                Some(argument(1, 0)?)
+                    .filter(|u| *u != "*")
+                    .map(String::from),
+            ),
+            _ => return Err(anyhow!("Unknown sdp transform '{}'", spec)),
+        };
+
+        Ok(SdpTransform { stages, transform })
+    }
+}
+
+/// Parses a comma separated list of transforms
+pub fn parse_transforms(spec: &str) -> Result<Vec<SdpTransform>> {
+    spec.split(SEPARATOR)
+        .filter(|s| !s.trim().is_empty())
+        .map(SdpTransform::from_str)
+        .collect()
+}
+
+/// Deserializes the transforms of a `SessionOptions` query parameter
+pub fn deserialize<'de, D>(deserializer: D) -> std::result::Result<Vec<SdpTransform>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let spec = String::deserialize(deserializer)?;
+    parse_transforms(&spec).map_err(serde::de::Error::custom)
+}
+
+/// Whether any of the transforms applies to the given stage
+pub fn applies(transforms: &[SdpTransform], stage: Stage) -> bool {
+    transforms.iter().any(|t| t.stages.contains(&stage))
+}
+
+/// Applies the transforms for the given stage to the sdp, in order
+pub fn apply(session: &mut SdpSession, transforms: &[SdpTransform], stage: Stage) {
+    transforms
+        .iter()
+        .filter(|t| t.stages.contains(&stage))
+        .for_each(|t| {
+            debug!("Applying sdp transform {:?} on {:?}", t.transform, stage);
+            apply_transform(session, &t.transform)
+        });
+}
+
+fn apply_transform(session: &mut SdpSession, transform: &Transform) {
+    if let Transform::Setup(role) = transform {
+        session.attribute.iter_mut().for_each(|a| {
+            if let SdpAttribute::Setup(_) = a {
+                *a = setup_attribute(role);
+            }
+        });
+    }
+
+    session.media = session
+        .media
+        .iter()
+        .map(|m| transform_media(m, transform))
+        .collect();
+}
+
+fn transform_media(media: &SdpMedia, transform: &Transform) -> SdpMedia {
+    let mut formats = media.get_formats().clone();
+    let mut bandwidth = media.get_bandwidth().clone();
+    let mut attributes = media.get_attributes().clone();
+
+    match transform {
+        Transform::Bandwidth {
+            media: kind,
+            bandwidth_type,
+            value,
+        } => {
+            if kind
+                .as_ref()
+                .map_or(true, |k| *k == media.get_type().to_string())
+            {
+                bandwidth.retain(|b| {
+                    !matches!(
+                        (b, bandwidth_type),
+                        (SdpBandwidth::As(_), BandwidthType::As)
+                            | (SdpBandwidth::Tias(_), BandwidthType::Tias)
+                    )
+                });
+                bandwidth.push(match bandwidth_type {
+                    BandwidthType::As => SdpBandwidth::As(*value),
+                    BandwidthType::Tias => SdpBandwidth::Tias(*value),
+                });
+            }
+        }
+        Transform::StripCodec(name) => {
+            let stripped = payload_types_of(&attributes, name, true);
+            let payload_types = match &formats {
+                SdpFormatList::Integers(p) => p.clone(),
+                _ => vec![],
+            };
+
+            if !stripped.is_empty() && payload_types.iter().all(|p| stripped.contains(p)) {
+                warn!(
+                    "Not stripping {}; it is the only codec of its media line",
+                    name
+                );
+            } else if !stripped.is_empty() {
+                formats = SdpFormatList::Integers(
+                    payload_types
+                        .into_iter()
+                        .filter(|p| !stripped.contains(p))
+                        .collect(),
+                );
+                attributes.retain(|a| match payload_type_of(a) {
+                    Some(p) => !stripped.contains(&p),
+                    None => true,
+                });
+            }
+        }
+        Transform::Fmtp { codec, parameters } => {
+            for pt in payload_types_of(&attributes, codec, false) {
+                let existing = attributes.iter().position(|a| match a {
+                    SdpAttribute::Fmtp(f) => u32::from(f.payload_type) == pt,
+                    _ => false,
+                });
+                let merged = match existing.map(|i| &attributes[i]) {
+                    Some(SdpAttribute::Fmtp(f)) => {
+                        merge_parameters(&f.parameters.to_string(), parameters)
+                    }
+                    _ => parameters.clone(),
+                };
+
+                let fmtp = match format!("fmtp:{} {}", pt, merged).parse::<SdpAttribute>() {
+                    Ok(f) => f,
+                    Err(e) => {
+                        warn!("Could not force fmtp {} for {}: {}", merged, codec, e);
+                        continue;
+                    }
+                };
+
+                match existing {
+                    Some(i) => attributes[i] = fmtp,
+                    None => {
+                        // right after the codec's rtpmap
+                        let rtpmap = attributes.iter().position(|a| match a {
+                            SdpAttribute::Rtpmap(r) => u32::from(r.payload_type) == pt,
+                            _ => false,
+                        });
+                        let at = rtpmap.map_or(attributes.len(), |i| i + 1);
+                        attributes.insert(at, fmtp);
+                    }
+                }
+            }
+        }
+        Transform::Setup(role) => attributes.iter_mut().for_each(|a| {
+            if let SdpAttribute::Setup(_) = a {
+                *a = setup_attribute(role);
+            }
+        }),
+        Transform::RemoveExtmap(uri) => attributes.retain(|a| match (a, uri) {
+            (SdpAttribute::Extmap(_), None) => false,
+            (SdpAttribute::Extmap(e), Some(u)) => e.url != *u,
+            _ => true,
+        }),
+    }
+
+    rebuild_media(media, formats, bandwidth, attributes)
+}
+
+/// SdpMedia only allows appending, so transformed media lines are built anew
+fn rebuild_media(
+    media: &SdpMedia,
+    formats: SdpFormatList,
+    bandwidth: Vec<SdpBandwidth>,
+    attributes: Vec<SdpAttribute>,
+) -> SdpMedia {
+    let mut rebuilt = SdpMedia::new(SdpMediaLine {
+        media: media.get_type().clone(),
+        port: media.get_port(),
+        port_count: media.get_port_count(),
+        proto: media.get_proto().clone(),
+        formats,
+    });
+
+    if let Some(c) = media.get_connection() {
+        rebuilt.set_connection(c.clone());
+    }
+    bandwidth.into_iter().for_each(|b| rebuilt.add_bandwidth(b));
+    attributes.into_iter().for_each(|a| {
+        if let Err(e) = rebuilt.add_attribute(a) {
+            warn!("Dropped attribute while munging sdp: {}", e);
+        }
+    });
+
+    rebuilt
+}
+
+/// The payload types of the codec with the given name, optionally including the rtx
+/// payload types associated with them
+fn payload_types_of(attributes: &[SdpAttribute], name: &str, with_rtx: bool) -> Vec<u32> {
+    let mut payload_types = attributes
+        .iter()
+        .filter_map(|a| match a {
+            SdpAttribute::Rtpmap(r) if r.codec_name.eq_ignore_ascii_case(name) => {
+                Some(u32::from(r.payload_type))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    if with_rtx {
+        let rtx = attributes
+            .iter()
+            .filter_map(|a| match a {
+                SdpAttribute::Fmtp(f) => f
+                    .parameters
+                    .rtx
+                    .as_ref()
+                    .filter(|r| payload_types.contains(&u32::from(r.apt)))
+                    .map(|_| u32::from(f.payload_type)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        payload_types.extend(rtx);
+    }
+
+    payload_types
+}
+
+/// The payload type a codec specific attribute refers to
+fn payload_type_of(attribute: &SdpAttribute) -> Option<u32> {
+    use webrtc_sdp::attribute_type::SdpAttributePayloadType;
+
+    match attribute {
+        SdpAttribute::Rtpmap(r) => Some(u32::from(r.payload_type)),
+        SdpAttribute::Fmtp(f) => Some(u32::from(f.payload_type)),
+        SdpAttribute::Rtcpfb(f) => match f.payload_type {
+            SdpAttributePayloadType::PayloadType(p) => Some(u32::from(p)),
+            SdpAttributePayloadType::Wildcard => None,
+        },
+        _ => None,
+    }
+}
+
+/// Overrides the `key=value` pairs of existing fmtp parameters with the forced ones,
+/// keeping the order of the existing ones
+fn merge_parameters(existing: &str, forced: &str) -> String {
+    let key = |p: &str| {
+        p.split('=')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase()
+    };
+    let forced = forced
+        .split(';')
+        .filter(|p| !p.trim().is_empty())
+        .collect::<Vec<_>>();
+
+    let mut merged = existing
+        .split(';')
+        .filter(|p| !p.trim().is_empty())
+        .map(|p| {
+            forced
+                .iter()
+                .find(|f| key(f) == key(p))
+                .copied()
+                .unwrap_or(p)
+        })
+        .collect::<Vec<_>>();
+
+    let added = forced
+        .iter()
+        .filter(|f| !merged.iter().any(|m| key(m) == key(f)))
+        .copied()
+        .collect::<Vec<_>>();
+    merged.extend(added);
+
+    merged.join(";")
+}
+
+fn setup_attribute(role: &str) -> SdpAttribute {
+    SdpAttribute::Setup(match role {
+        "active" => SdpAttributeSetup::Active,
+        "passive" => SdpAttributeSetup::Passive,
+        "holdconn" => SdpAttributeSetup::Holdconn,
+        _ => SdpAttributeSetup::Actpass,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SDP: &str = "v=0\r
+o=- 1 2 IN IP4 127.0.0.1\r
+s=-\r
+t=0 0\r
+a=setup:actpass\r
+m=audio 9 UDP/TLS/RTP/SAVPF 111\r
+c=IN IP4 0.0.0.0\r
+b=AS:64\r
+a=mid:0\r
+a=extmap:1 urn:ietf:params:rtp-hdrext:ssrc-audio-level\r
+a=extmap:4 urn:ietf:params:rtp-hdrext:sdes:mid\r
+a=rtpmap:111 opus/48000/2\r
+m=video 9 UDP/TLS/RTP/SAVPF 96 97 98\r
+c=IN IP4 0.0.0.0\r
+a=mid:1\r
+a=setup:actpass\r
+a=extmap:4 urn:ietf:params:rtp-hdrext:sdes:mid\r
+a=rtpmap:96 VP8/90000\r
+a=rtcp-fb:96 nack\r
+a=rtpmap:97 rtx/90000\r
+a=fmtp:97 apt=96\r
+a=rtpmap:98 H264/90000\r
+a=fmtp:98 profile-level-id=42e01f;packetization-mode=1\r
+";
+
+    fn munge(spec: &str, stage: Stage) -> SdpSession {
+        let mut session = webrtc_sdp::parse_sdp(SDP, false).unwrap();
+        apply(&mut session, &parse_transforms(spec).unwrap(), stage);
+        session
+    }
+
+    fn media_lines(session: &SdpSession) -> Vec<String> {
+        session.media.iter().map(|m| m.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_stages_and_arguments() {
+        let transforms = parse_transforms(
+            "send.bandwidth:*:tias:500000, recv.fmtp:VP8:max-fr=15;max-fs=3600,remove-extmap:*",
+        )
+        .unwrap();
+
+        assert_eq!(
+            transforms,
+            vec![
+                SdpTransform {
+                    stages: vec![Stage::Send],
+                    transform: Transform::Bandwidth {
+                        media: None,
+                        bandwidth_type: BandwidthType::Tias,
+                        value: 500000,
+                    },
+                },
+                SdpTransform {
+                    stages: vec![Stage::Receive],
+                    transform: Transform::Fmtp {
+                        codec: "VP8".to_string(),
+                        parameters: "max-fr=15;max-fs=3600".to_string(),
+                    },
+                },
+                SdpTransform {
+                    stages: vec![Stage::Send, Stage::Receive],
+                    transform: Transform::RemoveExtmap(None),
+                },
+            ]
+        );
+        assert!(applies(&transforms, Stage::Send));
+    }
+
+    #[test]
+    fn rejects_invalid_transforms() {
+        for spec in &[
+            "bandwidth:video:XX:5",
+            "bandwidth:video:AS",
+            "bandwidth:video:AS:fast",
+            "setup:sometimes",
+            "strip-codec:",
+            "mangle:everything",
+        ] {
+            assert!(parse_transforms(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn only_applies_transforms_of_the_stage() {
+        let session = munge("recv.setup:active", Stage::Send);
+
+        assert_eq!(
+            session.to_string(),
+            webrtc_sdp::parse_sdp(SDP, false).unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn bandwidth_replaces_the_line_of_its_type() {
+        let session = munge("bandwidth:audio:AS:32,bandwidth:*:TIAS:1000", Stage::Send);
+        let media = media_lines(&session);
+
+        assert!(media[0].contains("b=AS:32\r\n"));
+        assert!(!media[0].contains("b=AS:64"));
+        assert!(media[0].contains("b=TIAS:1000\r\n"));
+        assert!(!media[1].contains("b=AS"));
+        assert!(media[1].contains("b=TIAS:1000\r\n"));
+    }
+
+    #[test]
+    fn strip_codec_removes_its_rtx_and_attributes() {
+        let session = munge("strip-codec:vp8", Stage::Receive);
+        let video = &media_lines(&session)[1];
+
+        assert!(video.starts_with("m=video 9 UDP/TLS/RTP/SAVPF 98\r\n"));
+        assert!(!video.contains("VP8"));
+        assert!(!video.contains("rtx"));
+        assert!(!video.contains("a=rtcp-fb:96"));
+        assert!(video.contains("a=rtpmap:98 H264/90000"));
+    }
+
+    #[test]
+    fn strip_codec_keeps_the_only_codec() {
+        let session = munge("strip-codec:opus", Stage::Send);
+
+        assert!(media_lines(&session)[0].contains("a=rtpmap:111 opus/48000"));
+    }
+
+    #[test]
+    fn fmtp_merges_existing_and_adds_missing_parameters() {
+        let session = munge(
+            "fmtp:H264:profile-level-id=42e034,fmtp:VP8:max-fr=15",
+            Stage::Send,
+        );
+        let video = &media_lines(&session)[1];
+
+        assert!(
+            video.contains("a=fmtp:98 profile-level-id=42e034;packetization-mode=1"),
+            "{}",
+            video
+        );
+        assert!(video.contains("a=rtpmap:96 VP8/90000\r\na=fmtp:96 max-fr=15\r\n"));
+    }
+
+    #[test]
+    fn setup_rewrites_session_and_media_roles() {
+        let session = munge("setup:passive", Stage::Send);
+        let sdp = session.to_string();
+
+        assert!(!sdp.contains("a=setup:actpass"));
+        assert_eq!(sdp.matches("a=setup:passive").count(), 2);
+    }
+
+    #[test]
+    fn remove_extmap_removes_one_uri_or_all() {
+        let session = munge(
+            "remove-extmap:urn:ietf:params:rtp-hdrext:sdes:mid",
+            Stage::Send,
+        );
+        let media = media_lines(&session);
+        assert!(!media[0].contains("sdes:mid"));
+        assert!(media[0].contains("ssrc-audio-level"));
+        assert!(!media[1].contains("a=extmap"));
+
+        let session = munge("remove-extmap:*", Stage::Send);
+        assert!(!session.to_string().contains("a=extmap"));
+    }
+
+    #[test]
+    fn merges_parameters_in_order() {
+        assert_eq!(merge_parameters("a=1;b=2", "B=3;c=4"), "a=1;B=3;c=4");
+        assert_eq!(merge_parameters("", "c=4"), "c=4");
+    }
+}
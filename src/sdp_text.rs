@@ -0,0 +1,405 @@
+// Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
+
+//! Re-serialization of sdp payloads that were parsed and edited with webrtc_sdp.
+//!
+//! webrtc_sdp drops the lines it does not model, such as attributes it does not know, and
+//! writes the others in its own order. Rather than writing the edited session as a whole,
+//! its lines are merged into the original text: unchanged lines keep their text and
+//! position, lines webrtc_sdp does not model are kept as they are, and only added or
+//! changed lines are taken from the edited session.
+
+use std::collections::HashMap;
+
+use webrtc_sdp::{SdpSession, SdpType};
+
+const LINE_ENDING: &str = "\r\n";
+
+/// The order of the line types within a section (RFC 4566, section 5); `m=` starts a
+/// media section
+const LINE_ORDER: &str = "mvosiuepcbtrzka";
+
+/// Writes the edited session, keeping the text of the original sdp it was parsed from
+/// wherever the edits did not change it
+pub fn serialize(original: &str, edited: &SdpSession) -> String {
+    let original_sections = sections(lines(original));
+    let edited_sections = sections(lines(&edited.to_string()));
+    let modeled_sections = match webrtc_sdp::parse_sdp(original, false) {
+        Ok(s) => sections(lines(&s.to_string())),
+        Err(_) => vec![],
+    };
+
+    if original_sections.len() != edited_sections.len()
+        || original_sections.len() != modeled_sections.len()
+    {
+        warn!("The edited sdp does not match the original one, writing it as parsed");
+        return join(edited_sections.into_iter().flatten());
+    }
+
+    let merged = original_sections
+        .iter()
+        .zip(modeled_sections.iter())
+        .zip(edited_sections.iter())
+        .flat_map(|((original, modeled), edited)| merge_section(original, modeled, edited));
+
+    join(merged)
+}
+
+fn lines(sdp: &str) -> Vec<String> {
+    sdp.lines()
+        .map(str::trim_end)
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn join<I: Iterator<Item = String>>(lines: I) -> String {
+    lines.map(|l| l + LINE_ENDING).collect()
+}
+
+/// Splits the lines of an sdp into the session section and one section per media line
+fn sections(lines: Vec<String>) -> Vec<Vec<String>> {
+    let mut sections = vec![vec![]];
+
+    for line in lines {
+        if line.starts_with("m=") {
+            sections.push(vec![]);
+        }
+        sections.last_mut().unwrap().push(line);
+    }
+
+    sections
+}
+
+/// Merges a section of the edited session into the original one
+///
+/// `modeled` is the section as webrtc_sdp writes the original, i.e. the lines it models.
+fn merge_section(original: &[String], modeled: &[String], edited: &[String]) -> Vec<String> {
+    let mut modeled_lines = counts(modeled);
+    let mut edited_lines = counts(edited);
+
+    // an original line is kept if it is unchanged, or webrtc_sdp does not model it
+    let mut unchanged_lines = HashMap::new();
+    let kept = original
+        .iter()
+        .enumerate()
+        .map(|(n, l)| match canonical(l, n) {
+            Some(c) if take(&mut modeled_lines, &c) => {
+                let unchanged = take(&mut edited_lines, &c);
+                if unchanged {
+                    *unchanged_lines.entry(c).or_insert(0) += 1;
+                }
+                unchanged
+            }
+            _ => true,
+        })
+        .collect::<Vec<_>>();
+
+    // the edited lines that are not unchanged original ones are new
+    let new_lines = edited
+        .iter()
+        .filter(|l| !take(&mut unchanged_lines, l))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    // slot 0 is before the first line, slot n + 1 after line n
+    let mut inserted: Vec<Vec<String>> = vec![vec![]; original.len() + 1];
+    let mut removed_slots = HashMap::<String, Vec<usize>>::new();
+    let mut last_slots = HashMap::<String, usize>::new();
+    original.iter().enumerate().for_each(|(n, l)| {
+        if kept[n] {
+            last_slots.insert(key(l), n + 1);
+        } else {
+            removed_slots.entry(key(l)).or_default().push(n + 1);
+        }
+    });
+
+    for line in new_lines {
+        let k = key(&line);
+        let slot = match removed_slots.get_mut(&k).filter(|s| !s.is_empty()) {
+            // a changed line takes the place of the original one
+            Some(slots) => slots.remove(0),
+            None => match last_slots.get(&k) {
+                Some(slot) => *slot,
+                None => original
+                    .iter()
+                    .rposition(|l| rank(l) <= rank(&line))
+                    .map_or(0, |n| n + 1),
+            },
+        };
+        last_slots.insert(k, slot);
+        inserted[slot].push(line);
+    }
+
+    let mut merged = inserted[0].clone();
+    original.iter().enumerate().for_each(|(n, l)| {
+        if kept[n] {
+            merged.push(l.clone());
+        }
+        merged.extend(inserted[n + 1].iter().cloned());
+    });
+
+    merged
+}
+
+/// A line as webrtc_sdp writes it, or None if it does not model it
+fn canonical(line: &str, line_number: usize) -> Option<String> {
+    let parsed = webrtc_sdp::parse_sdp_line(line, line_number).ok()?;
+
+    Some(match parsed.sdp_type {
+        SdpType::Attribute(a) => format!("a={}", a),
+        SdpType::Bandwidth(b) => format!("b={}", b),
+        SdpType::Connection(c) => format!("c={}", c),
+        SdpType::Media(m) => format!("m={}", m),
+        SdpType::Origin(o) => format!("o={}", o),
+        SdpType::Session(s) => format!("s={}", s),
+        SdpType::Timing(t) => format!("t={}", t),
+        SdpType::Version(v) => format!("v={}", v),
+    })
+}
+
+/// The line type, and the attribute name for attributes
+fn key(line: &str) -> String {
+    if line.starts_with("a=") {
+        line.split(':').next().unwrap_or(line).to_string()
+    } else {
+        line.chars().take(2).collect()
+    }
+}
+
+fn rank(line: &str) -> usize {
+    line.chars()
+        .next()
+        .and_then(|t| LINE_ORDER.find(t))
+        .unwrap_or(LINE_ORDER.len())
+}
+
+fn counts(lines: &[String]) -> HashMap<String, usize> {
+    let mut counts = HashMap::new();
+    lines
+        .iter()
+        .for_each(|l| *counts.entry(l.clone()).or_insert(0) += 1);
+    counts
+}
+
+/// Takes one occurrence of the line, if there is any left
+fn take(counts: &mut HashMap<String, usize>, line: &str) -> bool {
+    match counts.get_mut(line) {
+        Some(n) if *n > 0 => {
+            *n -= 1;
+            true
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use webrtc_sdp::attribute_type::{SdpAttribute, SdpAttributeSetup, SdpAttributeType};
+
+    use crate::candidate::Candidate;
+
+    /// Offers and answers as generated by webrtcbin, with a few lines webrtc_sdp does not
+    /// model added to check that they survive
+    const GST_OFFER: &str = "v=0\r\n\
+        o=- 4962303333179871722 0 IN IP4 0.0.0.0\r\n\
+        s=-\r\n\
+        t=0 0\r\n\
+        a=ice-options:trickle\r\n\
+        a=group:BUNDLE video0 audio1\r\n\
+        m=video 9 UDP/TLS/RTP/SAVPF 96 97\r\n\
+        c=IN IP4 0.0.0.0\r\n\
+        a=setup:actpass\r\n\
+        a=ice-ufrag:8Mgb+p0C/Ab7qa+ZCJW7FpvFmVeKRsBP\r\n\
+        a=ice-pwd:vjAW0CkMxdQ9ktPKcxbEJEoDBnEC0tby\r\n\
+        a=rtcp-mux\r\n\
+        a=rtcp-rsize\r\n\
+        a=sendrecv\r\n\
+        a=rtpmap:96 VP8/90000\r\n\
+        a=rtcp-fb:96 nack\r\n\
+        a=rtcp-fb:96 nack pli\r\n\
+        a=x-google-flag:conference\r\n\
+        a=rtpmap:97 rtx/90000\r\n\
+        a=fmtp:97 apt=96\r\n\
+        a=ssrc-group:FID 3484078950 1290373464\r\n\
+        a=ssrc:3484078950 msid:user3265658012@host-ba9d4342 webrtctransceiver0\r\n\
+        a=ssrc:3484078950 cname:user3265658012@host-ba9d4342\r\n\
+        a=ssrc:1290373464 msid:user3265658012@host-ba9d4342 webrtctransceiver0\r\n\
+        a=ssrc:1290373464 cname:user3265658012@host-ba9d4342\r\n\
+        a=mid:video0\r\n\
+        a=fingerprint:sha-256 A2:8C:1C:69:41:9F:72:B4:C8:F0:36:C0:C2:06:6C:2B:1F:63:C4:83:FA:8C:19:D1:8F:30:04:45:7C:0E:DA:6D\r\n\
+        m=audio 0 UDP/TLS/RTP/SAVPF 98\r\n\
+        c=IN IP4 0.0.0.0\r\n\
+        a=setup:actpass\r\n\
+        a=ice-ufrag:8Mgb+p0C/Ab7qa+ZCJW7FpvFmVeKRsBP\r\n\
+        a=ice-pwd:vjAW0CkMxdQ9ktPKcxbEJEoDBnEC0tby\r\n\
+        a=bundle-only\r\n\
+        a=rtcp-mux\r\n\
+        a=rtcp-rsize\r\n\
+        a=sendrecv\r\n\
+        a=rtpmap:98 OPUS/48000/2\r\n\
+        a=rtcp-fb:98 nack\r\n\
+        a=ts-refclk:ntp=/traceable/\r\n\
+        a=ssrc:2846283437 msid:user3265658012@host-ba9d4342 webrtctransceiver1\r\n\
+        a=ssrc:2846283437 cname:user3265658012@host-ba9d4342\r\n\
+        a=mid:audio1\r\n\
+        a=fingerprint:sha-256 A2:8C:1C:69:41:9F:72:B4:C8:F0:36:C0:C2:06:6C:2B:1F:63:C4:83:FA:8C:19:D1:8F:30:04:45:7C:0E:DA:6D\r\n";
+
+    const GST_ANSWER: &str = "v=0\r\n\
+        o=- 2173851962203437519 0 IN IP4 0.0.0.0\r\n\
+        s=-\r\n\
+        t=0 0\r\n\
+        a=ice-options:trickle\r\n\
+        a=group:BUNDLE 0\r\n\
+        m=video 9 UDP/TLS/RTP/SAVPF 96\r\n\
+        c=IN IP4 0.0.0.0\r\n\
+        a=ice-ufrag:gdaVHp8SLz4xPyDbq7TNVMlvS2xoe6R7\r\n\
+        a=ice-pwd:HT0dAKyKjzOYU8c4rJbOA2xl4IvSDCfO\r\n\
+        a=mid:0\r\n\
+        a=setup:active\r\n\
+        a=rtcp-mux\r\n\
+        a=sendrecv\r\n\
+        a=rtpmap:96 VP8/90000\r\n\
+        a=rtcp-fb:96 nack pli\r\n\
+        a=rtcp-fb:96 ccm fir\r\n\
+        a=extmap-allow-mixed\r\n\
+        a=ssrc:1764562417 cname:user1457127733@host-79ac1d63\r\n\
+        a=fingerprint:sha-256 3D:4C:E8:22:95:8B:6E:5C:03:53:BF:55:47:6A:E5:1B:5C:0D:23:02:52:43:DE:F3:B5:58:F7:68:13:82:7A:7D\r\n";
+
+    const FIRST_CANDIDATE: &str = "candidate:1 1 UDP 2015363327 192.168.1.10 53492 typ host";
+    const SECOND_CANDIDATE: &str =
+        "candidate:3 1 UDP 1679815423 203.0.113.7 53492 typ srflx raddr 192.168.1.10 rport 53492";
+
+    fn parse(sdp: &str) -> SdpSession {
+        webrtc_sdp::parse_sdp(sdp, false).unwrap()
+    }
+
+    fn candidate(raw: &str) -> SdpAttribute {
+        SdpAttribute::Candidate(Candidate::from_str(raw).unwrap().into())
+    }
+
+    fn add_candidates(sdp: &str, media_line: usize, candidates: &[&str]) -> SdpSession {
+        let mut session = parse(sdp);
+        candidates.iter().for_each(|c| {
+            session.media[media_line]
+                .add_attribute(candidate(c))
+                .unwrap()
+        });
+        session.media[media_line]
+            .add_attribute(SdpAttribute::EndOfCandidates)
+            .unwrap();
+        session
+    }
+
+    /// The original sdp with the given lines inserted after the last line of a media section
+    fn with_lines_after_media(sdp: &str, media_line: usize, added: &[String]) -> String {
+        let mut lines = lines(sdp);
+        let media_starts = lines
+            .iter()
+            .enumerate()
+            .filter(|(_, l)| l.starts_with("m="))
+            .map(|(n, _)| n)
+            .collect::<Vec<_>>();
+        let end = media_starts
+            .get(media_line + 1)
+            .copied()
+            .unwrap_or(lines.len());
+        added
+            .iter()
+            .rev()
+            .for_each(|l| lines.insert(end, l.clone()));
+        join(lines.into_iter())
+    }
+
+    #[test]
+    fn unchanged_sdp_is_written_as_is() {
+        for sdp in [GST_OFFER, GST_ANSWER].iter() {
+            assert_eq!(serialize(sdp, &parse(sdp)), *sdp);
+        }
+    }
+
+    #[test]
+    fn writes_no_blank_lines() {
+        let session = add_candidates(GST_OFFER, 0, &[FIRST_CANDIDATE]);
+        assert!(!serialize(GST_OFFER, &session).contains("\r\n\r\n"));
+    }
+
+    #[test]
+    fn candidates_are_appended_to_their_media_section() {
+        let session = add_candidates(GST_OFFER, 0, &[FIRST_CANDIDATE, SECOND_CANDIDATE]);
+
+        let expected = with_lines_after_media(
+            GST_OFFER,
+            0,
+            &[
+                format!("a={}", FIRST_CANDIDATE),
+                format!("a={}", SECOND_CANDIDATE),
+                "a=end-of-candidates".to_string(),
+            ],
+        );
+        assert_eq!(serialize(GST_OFFER, &session), expected);
+    }
+
+    #[test]
+    fn candidates_are_inserted_into_answers() {
+        let session = add_candidates(GST_ANSWER, 0, &[FIRST_CANDIDATE]);
+
+        let expected = with_lines_after_media(
+            GST_ANSWER,
+            0,
+            &[
+                format!("a={}", FIRST_CANDIDATE),
+                "a=end-of-candidates".to_string(),
+            ],
+        );
+        assert_eq!(serialize(GST_ANSWER, &session), expected);
+    }
+
+    #[test]
+    fn unmodeled_attributes_survive_candidate_insertion() {
+        let session = add_candidates(GST_OFFER, 1, &[FIRST_CANDIDATE]);
+        let written = serialize(GST_OFFER, &session);
+
+        // webrtc_sdp itself drops them
+        assert!(!session.to_string().contains("a=ts-refclk"));
+        for line in ["a=x-google-flag:conference", "a=ts-refclk:ntp=/traceable/"].iter() {
+            assert!(written.contains(line), "{} is missing:\n{}", line, written);
+        }
+    }
+
+    #[test]
+    fn removed_attributes_are_removed_in_place() {
+        let mut session = parse(GST_OFFER);
+        session
+            .media
+            .iter_mut()
+            .for_each(|m| m.remove_attribute(SdpAttributeType::RtcpMux));
+
+        let expected = GST_OFFER.replace("a=rtcp-mux\r\n", "");
+        assert_eq!(serialize(GST_OFFER, &session), expected);
+    }
+
+    #[test]
+    fn changed_attributes_keep_their_position() {
+        let mut session = parse(GST_OFFER);
+        session.media[0]
+            .set_attribute(SdpAttribute::Setup(SdpAttributeSetup::Passive))
+            .unwrap();
+
+        let expected = GST_OFFER.replacen("a=setup:actpass", "a=setup:passive", 1);
+        assert_eq!(serialize(GST_OFFER, &session), expected);
+    }
+
+    #[test]
+    fn session_attributes_are_appended_to_the_session_section() {
+        let mut session = parse(GST_ANSWER);
+        session.attribute.push(SdpAttribute::IceLite);
+
+        let expected =
+            GST_ANSWER.replace("a=group:BUNDLE 0\r\n", "a=group:BUNDLE 0\r\na=ice-lite\r\n");
+        assert_eq!(serialize(GST_ANSWER, &session), expected);
+    }
+}
@@ -0,0 +1,298 @@
+// Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
+
+//! Simulcast negotiation (RFC 8853): the remote party sends several encodings of its
+//! video, the layers, each identified by a rid. Webrtcbin does not negotiate simulcast, so
+//! the `a=simulcast` and `a=rid` lines are added to the descriptions sent to the remote
+//! party. Webrtcbin decodes only the first layer of a media line, so only that layer is
+//! negotiated to be sent: the others are offered paused, and left out of the answer.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use webrtc_sdp::attribute_type::{
+    SdpAttribute, SdpAttributeSimulcast, SdpAttributeSimulcastVersion, SdpAttributeType,
+    SdpSingleDirection,
+};
+use webrtc_sdp::media_type::{SdpMedia, SdpMediaValue};
+use webrtc_sdp::SdpSession;
+
+use super::extmap::{extmap_id, MID_URI};
+
+/// The header extension carrying the rid of a layer's packets
+pub const RID_URI: &str = "urn:ietf:params:rtp-hdrext:sdes:rtp-stream-id";
+const MAX_ONE_BYTE_EXTMAP_ID: u16 = 14;
+
+/// Parses the rids to offer, as a comma separated list, e.g. `h,m,l`. Rids follow the
+/// rid-id grammar of RFC 8851, letters, digits, `-` and `_`, and are unique.
+pub fn deserialize<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let spec = String::deserialize(deserializer)?;
+    parse_rids(&spec).map_err(D::Error::custom)
+}
+
+fn parse_rids(spec: &str) -> std::result::Result<Vec<String>, String> {
+    let mut rids: Vec<String> = vec![];
+    for rid in spec.split(',').map(str::trim).filter(|rid| !rid.is_empty()) {
+        if !is_rid_id(rid) {
+            return Err(format!("Invalid rid {}", rid));
+        }
+        if rids.iter().any(|r| r == rid) {
+            return Err(format!("Duplicate rid {}", rid));
+        }
+        rids.push(rid.to_string());
+    }
+
+    Ok(rids)
+}
+
+/// rid-id = 1*(alpha-numeric / "-" / "_")
+fn is_rid_id(rid: &str) -> bool {
+    !rid.is_empty()
+        && rid
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn attribute(line: &str) -> Result<SdpAttribute> {
+    SdpAttribute::from_str(line).map_err(|e| anyhow!("Invalid attribute a={}: {}", line, e))
+}
+
+fn simulcast_of(media: &SdpMedia) -> Option<&SdpAttributeSimulcast> {
+    match media.get_attribute(SdpAttributeType::Simulcast) {
+        Some(SdpAttribute::Simulcast(s)) => Some(s),
+        _ => None,
+    }
+}
+
+/// The first rid of every alternative in a simulcast stream list that is not paused
+fn rids(versions: &[SdpAttributeSimulcastVersion]) -> Vec<String> {
+    versions
+        .iter()
+        .filter_map(|v| v.ids.first())
+        .filter(|id| !id.paused)
+        .map(|id| id.id.clone())
+        .collect()
+}
+
+fn can_receive(media: &SdpMedia) -> bool {
+    media.get_port() != 0
+        && media.get_attribute(SdpAttributeType::Sendonly).is_none()
+        && media.get_attribute(SdpAttributeType::Inactive).is_none()
+}
+
+/// Offers to receive the rids on every video media line that receives, with every layer but
+/// the first paused
+pub fn offer(session: &mut SdpSession, rids: &[String]) -> Result<()> {
+    if rids.is_empty() {
+        return Ok(());
+    }
+
+    // with bundle, the media lines share the ids of their header extensions
+    let rid_extmap = session
+        .media
+        .iter()
+        .filter_map(|m| extmap_id(m, RID_URI))
+        .next()
+        .or_else(|| {
+            let used = session
+                .media
+                .iter()
+                .flat_map(|m| m.get_attributes())
+                .filter_map(|a| match a {
+                    SdpAttribute::Extmap(e) => Some(e.id),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            (1..=MAX_ONE_BYTE_EXTMAP_ID).find(|id| !used.contains(id))
+        });
+
+    for media in session
+        .media
+        .iter_mut()
+        .filter(|m| *m.get_type() == SdpMediaValue::Video && can_receive(m))
+    {
+        add_rid_extmap(media, rid_extmap)?;
+        for rid in rids {
+            add(media, &format!("rid:{} recv", rid))?;
+        }
+        add(
+            media,
+            &format!("simulcast:recv {}", paused_after_first(rids)),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Accepts, in the answer, the first layer of the simulcast the offer sends on each media
+/// line; the others would not be received
+pub fn answer(offer: &SdpSession, answer: &mut SdpSession) -> Result<()> {
+    for (offered, answered) in offer.media.iter().zip(answer.media.iter_mut()) {
+        let first = match simulcast_of(offered).map(|s| rids(&s.send)) {
+            Some(rids) if !rids.is_empty() => rids[0].clone(),
+            _ => continue,
+        };
+        if answered.get_port() == 0 || simulcast_of(answered).is_some() {
+            continue;
+        }
+
+        // the remote party only sends the header extensions the answer accepts
+        for uri in &[RID_URI, MID_URI] {
+            if let (Some(id), None) = (extmap_id(offered, uri), extmap_id(answered, uri)) {
+                add(answered, &format!("extmap:{} {}", id, uri))?;
+            }
+        }
+
+        let sends_first = offered.get_attributes().iter().any(|a| match a {
+            SdpAttribute::Rid(r) => r.direction == SdpSingleDirection::Send && r.id == first,
+            _ => false,
+        });
+        if !sends_first {
+            warn!("The offer has no a=rid:{} send for its simulcast", first);
+            continue;
+        }
+        add(answered, &format!("rid:{} recv", first))?;
+        add(answered, &format!("simulcast:recv {}", first))?;
+    }
+
+    Ok(())
+}
+
+/// `h;~m;~l` for `h,m,l`
+fn paused_after_first(rids: &[String]) -> String {
+    rids.iter()
+        .enumerate()
+        .map(|(i, rid)| {
+            if i == 0 {
+                rid.clone()
+            } else {
+                format!("~{}", rid)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+fn add_rid_extmap(media: &mut SdpMedia, id: Option<u16>) -> Result<()> {
+    match (id, extmap_id(media, RID_URI)) {
+        (Some(id), None) => add(media, &format!("extmap:{} {}", id, RID_URI))?,
+        (None, None) => warn!("No extmap id left for the rid header extension"),
+        _ => (),
+    }
+
+    Ok(())
+}
+
+fn add(media: &mut SdpMedia, line: &str) -> Result<()> {
+    let attribute = attribute(line)?;
+    if let Err(e) = media.add_attribute(attribute) {
+        warn!("Could not add a={}: {}", line, e);
+    }
+
+    Ok(())
+}
+
+/// Whether any media line of the offer sends simulcast
+pub fn offered(offer: &SdpSession) -> bool {
+    offer
+        .media
+        .iter()
+        .any(|m| simulcast_of(m).map_or(false, |s| !s.send.is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER: &str = "v=0\r\n\
+        o=- 1 1 IN IP4 0.0.0.0\r\n\
+        s=-\r\n\
+        t=0 0\r\n\
+        m=video 9 UDP/TLS/RTP/SAVPF 96\r\n\
+        c=IN IP4 0.0.0.0\r\n\
+        a=mid:0\r\n\
+        a=sendonly\r\n\
+        a=rtpmap:96 VP8/90000\r\n\
+        a=extmap:4 urn:ietf:params:rtp-hdrext:sdes:mid\r\n\
+        a=extmap:10 urn:ietf:params:rtp-hdrext:sdes:rtp-stream-id\r\n\
+        a=rid:h send\r\n\
+        a=rid:l send\r\n\
+        a=simulcast:send h;l\r\n";
+
+    const ANSWER: &str = "v=0\r\n\
+        o=- 2 1 IN IP4 0.0.0.0\r\n\
+        s=-\r\n\
+        t=0 0\r\n\
+        m=video 9 UDP/TLS/RTP/SAVPF 96\r\n\
+        c=IN IP4 0.0.0.0\r\n\
+        a=mid:0\r\n\
+        a=recvonly\r\n\
+        a=rtpmap:96 VP8/90000\r\n";
+
+    fn parse(sdp: &str) -> SdpSession {
+        webrtc_sdp::parse_sdp(sdp, false).expect("Invalid sdp")
+    }
+
+    #[test]
+    fn reads_whether_layers_are_offered() {
+        assert!(offered(&parse(OFFER)));
+        assert!(!offered(&parse(ANSWER)));
+    }
+
+    #[test]
+    fn parses_the_rids_to_offer() {
+        assert_eq!(
+            parse_rids(" h, m_1 ,,l-2"),
+            Ok(vec!["h".to_string(), "m_1".to_string(), "l-2".to_string()])
+        );
+        assert_eq!(parse_rids(""), Ok(vec![]));
+        assert!(parse_rids("h;l").is_err());
+        assert!(parse_rids("h,l\r\na=inactive").is_err());
+        assert!(parse_rids("h,h").is_err());
+    }
+
+    #[test]
+    fn answers_only_the_first_offered_layer() {
+        let offer = parse(OFFER);
+        let mut answer = parse(ANSWER);
+        super::answer(&offer, &mut answer).unwrap();
+
+        let text = answer.to_string();
+        assert!(text.contains("a=simulcast:recv h\r\n"), "{}", text);
+        assert!(text.contains("a=rid:h recv"), "{}", text);
+        assert!(!text.contains("a=rid:l"), "{}", text);
+        assert!(
+            text.contains(&format!("a=extmap:10 {}", RID_URI)),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains(&format!("a=extmap:4 {}", MID_URI)),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn offers_the_rids_on_receiving_video() {
+        let mut session = parse(ANSWER);
+        session.media[0]
+            .add_attribute(attribute("extmap:1 urn:ietf:params:rtp-hdrext:toffset").unwrap())
+            .unwrap();
+        super::offer(&mut session, &["f".to_string(), "q".to_string()]).unwrap();
+
+        let text = session.to_string();
+        assert!(text.contains("a=simulcast:recv f;~q"), "{}", text);
+        assert!(text.contains("a=rid:f recv"), "{}", text);
+        assert!(text.contains("a=rid:q recv"), "{}", text);
+        assert!(
+            text.contains(&format!("a=extmap:2 {}", RID_URI)),
+            "{}",
+            text
+        );
+    }
+}
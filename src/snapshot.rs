@@ -0,0 +1,117 @@
+// Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
+
+//! Snapshots of incoming video. The sink of every incoming video stream keeps the last frame
+//! it received, which is encoded as a png or jpeg on request, in a pipeline of its own.
+
+use gst::prelude::*;
+use gstreamer as gst;
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+use super::gstlib::*;
+
+const SINK_PREFIX: &str = "video-sink-";
+const ENCODE_TIMEOUT_NS: u64 = 2_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+}
+
+impl Default for ImageFormat {
+    fn default() -> Self {
+        ImageFormat::Png
+    }
+}
+
+impl ImageFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+        }
+    }
+
+    fn encoder(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "pngenc",
+            ImageFormat::Jpeg => "jpegenc",
+        }
+    }
+}
+
+/// The launch description of the destination of an incoming video stream, keeping its last
+/// frame for snapshots
+pub fn destination_description(stream: &str) -> String {
+    format!(
+        "queue ! videoconvert ! videoscale ! fakesink name={}{} enable-last-sample=true",
+        SINK_PREFIX, stream
+    )
+}
+
+/// The last frame of an incoming video stream, by the name of its webrtcbin pad, e.g.
+/// `src_1`, or of the first one that has a frame
+pub fn latest_frame(pipeline: &gst::Pipeline, stream: Option<&str>) -> Option<gst::Sample> {
+    let name = stream.map(|s| format!("{}{}", SINK_PREFIX, s));
+
+    pipeline
+        .iterate_recurse()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| match name {
+            Some(ref name) => e.get_name() == name.as_str(),
+            None => e.get_name().starts_with(SINK_PREFIX),
+        })
+        .find_map(|e| {
+            e.get_property("last-sample")
+                .ok()
+                .and_then(|v| v.get::<gst::Sample>().ok().flatten())
+        })
+}
+
+/// Encodes a frame as an image
+pub fn encode(sample: &gst::Sample, format: ImageFormat) -> Result<Vec<u8>> {
+    let caps = sample
+        .get_caps()
+        .ok_or_else(|| anyhow!("The frame has no caps"))?;
+
+    let pipeline = gst::parse_launch(&format!(
+        "appsrc name=source format=time ! videoconvert ! {} ! appsink name=sink sync=false",
+        format.encoder()
+    ))?
+    .to_pipeline();
+    let source = pipeline.get_by_name("source").unwrap();
+    let sink = pipeline.get_by_name("sink").unwrap();
+    source.set_property("caps", &caps.to_owned())?;
+
+    pipeline.set_state(gst::State::Playing)?;
+    let encoded = push_frame(&source, &sink, sample);
+    pipeline.set_state(gst::State::Null)?;
+
+    let encoded = encoded?;
+    let buffer = encoded
+        .get_buffer()
+        .ok_or_else(|| anyhow!("The image has no buffer"))?;
+    let map = buffer
+        .map_readable()
+        .map_err(|_| anyhow!("Could not read the image"))?;
+
+    Ok(map.as_slice().to_vec())
+}
+
+/// Pushes the frame into the encoding pipeline, and waits for the image
+fn push_frame(
+    source: &gst::Element,
+    sink: &gst::Element,
+    sample: &gst::Sample,
+) -> Result<gst::Sample> {
+    source.emit("push-sample", &[sample])?;
+    source.emit("end-of-stream", &[])?;
+
+    sink.emit("try-pull-sample", &[&ENCODE_TIMEOUT_NS])?
+        .and_then(|v| v.get::<gst::Sample>().ok().flatten())
+        .ok_or_else(|| anyhow!("The frame was not encoded in time"))
+}
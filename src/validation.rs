@@ -0,0 +1,534 @@
+// Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
+
+//! Detailed validation of remote sdp payloads: every line webrtc_sdp could not parse, the
+//! warnings of its permissive parsing, and semantic checks that webrtcbin would otherwise
+//! only report as a failed negotiation.
+
+use std::fmt;
+
+use serde::Serialize;
+use webrtc_sdp::error::SdpParserError;
+use webrtc_sdp::SdpSession;
+
+use super::inspect;
+use super::inspect::{MediaSummary, SdpSummary};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A single problem with an sdp; line numbers are one-based
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub line_number: Option<usize>,
+    pub line: Option<String>,
+    pub media_line: Option<usize>,
+    pub message: String,
+}
+
+/// The outcome of validating an sdp; it is valid if there are no errors
+#[derive(Debug, Clone, Serialize)]
+pub struct ValidationReport {
+    pub valid: bool,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl ValidationReport {
+    fn new(diagnostics: Vec<Diagnostic>) -> Self {
+        ValidationReport {
+            valid: !diagnostics.iter().any(|d| d.severity == Severity::Error),
+            diagnostics,
+        }
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The provided sdp is not valid.")?;
+        for d in self.diagnostics.iter() {
+            write!(f, "\n  {:?}", d.severity)?;
+            if let Some(n) = d.line_number {
+                write!(f, " at line {}", n)?;
+            }
+            if let Some(m) = d.media_line {
+                write!(f, " in media line {}", m)?;
+            }
+            write!(f, ": {}", d.message)?;
+            if let Some(l) = &d.line {
+                write!(f, " ({})", l)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationReport {}
+
+/// Parses an sdp, reporting every line that could not be parsed rather than only the
+/// last one. Warnings of the permissive parsing are logged.
+pub fn parse(sdp: &str) -> Result<SdpSession, ValidationReport> {
+    let (session, diagnostics) = parse_with_diagnostics(sdp);
+    finish(session, diagnostics)
+}
+
+/// Parses and checks a remote description. With the offer it answers, the answer is also
+/// checked against it: the number of media lines, the codecs and the directions.
+pub fn validate(sdp: &str, offer: Option<&SdpSession>) -> Result<SdpSession, ValidationReport> {
+    let (session, mut diagnostics) = parse_with_diagnostics(sdp);
+
+    if let Some(s) = &session {
+        let summary = inspect::summarize(s);
+        diagnostics.extend(check_transport(&summary, sdp));
+
+        if let Some(o) = offer {
+            diagnostics.extend(check_answer(&inspect::summarize(o), &summary, sdp));
+        }
+    }
+
+    finish(session, diagnostics)
+}
+
+fn finish(
+    session: Option<SdpSession>,
+    diagnostics: Vec<Diagnostic>,
+) -> Result<SdpSession, ValidationReport> {
+    let report = ValidationReport::new(diagnostics);
+
+    match session {
+        Some(s) if report.valid => {
+            report
+                .diagnostics
+                .iter()
+                .for_each(|d| warn!("Sdp warning: {}", describe(d)));
+            Ok(s)
+        }
+        _ => Err(ValidationReport {
+            valid: false,
+            ..report
+        }),
+    }
+}
+
+fn describe(d: &Diagnostic) -> String {
+    match (&d.line_number, &d.line) {
+        (Some(n), Some(l)) => format!("{} at line {} ({})", d.message, n, l),
+        _ => d.message.clone(),
+    }
+}
+
+fn parse_with_diagnostics(sdp: &str) -> (Option<SdpSession>, Vec<Diagnostic>) {
+    // the parser only returns the last failing line, so report every line on its own; this
+    // includes the unsupported lines that permissive parsing turns into warnings
+    let mut diagnostics = sdp
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+        .filter_map(|(n, l)| webrtc_sdp::parse_sdp_line(l, n).err())
+        .map(|e| from_parser_error(&e))
+        .collect::<Vec<_>>();
+
+    match webrtc_sdp::parse_sdp(sdp, false) {
+        Ok(session) => (Some(session), diagnostics),
+        Err(e) => {
+            let error = from_parser_error(&e);
+            let reported = diagnostics
+                .iter()
+                .any(|d| d.line_number == error.line_number && d.message == error.message);
+            if !reported {
+                diagnostics.push(error);
+            }
+            (None, diagnostics)
+        }
+    }
+}
+
+fn from_parser_error(e: &SdpParserError) -> Diagnostic {
+    let (severity, line_number, line, message) = match e {
+        SdpParserError::Line {
+            error,
+            line,
+            line_number,
+        } => (
+            Severity::Error,
+            *line_number,
+            Some(line.clone()),
+            error.to_string(),
+        ),
+        SdpParserError::Unsupported {
+            error,
+            line,
+            line_number,
+        } => (
+            Severity::Warning,
+            *line_number,
+            Some(line.clone()),
+            error.to_string(),
+        ),
+        SdpParserError::Sequence {
+            message,
+            line_number,
+        } => (Severity::Error, *line_number, None, message.clone()),
+    };
+
+    Diagnostic {
+        severity,
+        line_number: Some(line_number + 1),
+        line,
+        media_line: None,
+        message,
+    }
+}
+
+/// Every media line that carries a transport needs ice credentials and a dtls fingerprint,
+/// either of its own or at the session level
+fn check_transport(summary: &SdpSummary, sdp: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+
+    summary
+        .media
+        .iter()
+        .filter(|m| carries_transport(summary, m))
+        .for_each(|m| {
+            let mut missing = |what: &str, present: bool| {
+                if !present {
+                    diagnostics.push(media_error(
+                        sdp,
+                        m.index,
+                        format!("Missing {} for media line {}", what, m.index),
+                    ));
+                }
+            };
+
+            missing(
+                "a=ice-ufrag",
+                m.ice_ufrag.is_some() || summary.ice_ufrag.is_some(),
+            );
+            missing(
+                "a=ice-pwd",
+                m.ice_pwd.is_some() || summary.ice_pwd.is_some(),
+            );
+            missing(
+                "a=fingerprint",
+                !m.fingerprints.is_empty() || !summary.fingerprints.is_empty(),
+            );
+        });
+
+    diagnostics
+}
+
+/// Checks an answer against the offer it answers (RFC 3264, section 6)
+fn check_answer(offer: &SdpSummary, answer: &SdpSummary, sdp: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+
+    if offer.media.len() != answer.media.len() {
+        diagnostics.push(error(format!(
+            "The answer has {} media lines, but the offer has {}",
+            answer.media.len(),
+            offer.media.len()
+        )));
+    }
+
+    offer
+        .media
+        .iter()
+        .zip(answer.media.iter())
+        .filter(|(_, a)| a.port != 0 || a.bundle_only)
+        .for_each(|(o, a)| {
+            if o.kind != a.kind {
+                diagnostics.push(media_error(
+                    sdp,
+                    a.index,
+                    format!(
+                        "Media line {} is {}, but {} was offered",
+                        a.index, a.kind, o.kind
+                    ),
+                ));
+            }
+
+            if o.mid.is_some() && a.mid != o.mid {
+                diagnostics.push(media_error(
+                    sdp,
+                    a.index,
+                    format!(
+                        "Media line {} has mid {:?}, but {:?} was offered",
+                        a.index, a.mid, o.mid
+                    ),
+                ));
+            }
+
+            a.codecs
+                .iter()
+                .filter(|c| {
+                    !o.codecs.iter().any(|offered| {
+                        offered.payload_type == c.payload_type
+                            && offered.name.eq_ignore_ascii_case(&c.name)
+                    })
+                })
+                .for_each(|c| {
+                    diagnostics.push(media_error(
+                        sdp,
+                        a.index,
+                        format!(
+                            "Codec {} {} in media line {} was not offered",
+                            c.payload_type, c.name, a.index
+                        ),
+                    ))
+                });
+
+            if !inspect::direction_compatible(&o.direction, &a.direction) {
+                diagnostics.push(media_error(
+                    sdp,
+                    a.index,
+                    format!(
+                        "Direction {} in media line {} does not answer the offered {}",
+                        a.direction, a.index, o.direction
+                    ),
+                ));
+            }
+
+            if a.setup.as_deref().or(answer.setup.as_deref()) == Some("actpass") {
+                diagnostics.push(media_error(
+                    sdp,
+                    a.index,
+                    format!("Media line {} answers a=setup:actpass", a.index),
+                ));
+            }
+        });
+
+    diagnostics
+}
+
+/// Whether a media line has a transport of its own: it is not rejected, and it is either
+/// not bundled or the first media line of its bundle group
+fn carries_transport(summary: &SdpSummary, media: &MediaSummary) -> bool {
+    if media.port == 0 && !media.bundle_only {
+        return false;
+    }
+
+    let mid = match &media.mid {
+        Some(mid) => mid,
+        None => return true,
+    };
+
+    let tag = summary
+        .groups
+        .iter()
+        .map(|g| g.split_whitespace().collect::<Vec<_>>())
+        .find(|g| g.first() == Some(&"BUNDLE") && g.contains(&mid.as_str()))
+        .and_then(|g| {
+            g.into_iter()
+                .skip(1)
+                .find(|tag| summary.media.iter().any(|m| m.mid.as_deref() == Some(*tag)))
+        });
+
+    tag.map_or(true, |t| t == mid)
+}
+
+fn error(message: String) -> Diagnostic {
+    Diagnostic {
+        severity: Severity::Error,
+        line_number: None,
+        line: None,
+        media_line: None,
+        message,
+    }
+}
+
+/// An error about a media line, pointing at its m= line
+fn media_error(sdp: &str, media_line: usize, message: String) -> Diagnostic {
+    let m_line = sdp
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| l.starts_with("m="))
+        .nth(media_line);
+
+    Diagnostic {
+        severity: Severity::Error,
+        line_number: m_line.map(|(n, _)| n + 1),
+        line: m_line.map(|(_, l)| l.to_string()),
+        media_line: Some(media_line),
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER: &str = "v=0\r
+o=- 1 2 IN IP4 127.0.0.1\r
+s=-\r
+t=0 0\r
+a=group:BUNDLE 0 1\r
+a=ice-ufrag:offr\r
+a=ice-pwd:offerpasswordoffer12345\r
+a=fingerprint:sha-256 FINGERPRINT\r
+m=audio 9 UDP/TLS/RTP/SAVPF 111\r
+c=IN IP4 0.0.0.0\r
+a=mid:0\r
+a=sendrecv\r
+a=setup:actpass\r
+a=rtpmap:111 opus/48000/2\r
+m=video 9 UDP/TLS/RTP/SAVPF 96\r
+c=IN IP4 0.0.0.0\r
+a=mid:1\r
+a=sendonly\r
+a=setup:actpass\r
+a=rtpmap:96 VP8/90000\r
+";
+
+    const ANSWER: &str = "v=0\r
+o=- 3 4 IN IP4 127.0.0.1\r
+s=-\r
+t=0 0\r
+a=group:BUNDLE 0 1\r
+a=ice-ufrag:answ\r
+a=ice-pwd:answerpasswordanswer1234\r
+a=fingerprint:sha-256 FINGERPRINT\r
+m=audio 9 UDP/TLS/RTP/SAVPF 111\r
+c=IN IP4 0.0.0.0\r
+a=mid:0\r
+a=recvonly\r
+a=setup:active\r
+a=rtpmap:111 opus/48000/2\r
+m=video 9 UDP/TLS/RTP/SAVPF 96\r
+c=IN IP4 0.0.0.0\r
+a=mid:1\r
+a=recvonly\r
+a=setup:active\r
+a=rtpmap:96 VP8/90000\r
+";
+
+    const FINGERPRINT: &str = "AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB:AB";
+
+    fn fixture(sdp: &str) -> String {
+        sdp.replace("FINGERPRINT", FINGERPRINT)
+    }
+
+    fn check(answer: &str) -> Vec<Diagnostic> {
+        let offer = parse(&fixture(OFFER)).unwrap();
+        let answer = fixture(answer);
+        let parsed = parse(&answer).unwrap();
+
+        check_answer(
+            &inspect::summarize(&offer),
+            &inspect::summarize(&parsed),
+            &answer,
+        )
+    }
+
+    fn messages(diagnostics: &[Diagnostic]) -> Vec<&str> {
+        diagnostics.iter().map(|d| d.message.as_str()).collect()
+    }
+
+    #[test]
+    fn matching_answer_has_no_diagnostics() {
+        let offer = parse(&fixture(OFFER)).unwrap();
+
+        assert!(check(ANSWER).is_empty());
+        assert!(validate(&fixture(ANSWER), Some(&offer)).is_ok());
+    }
+
+    #[test]
+    fn reports_a_different_number_of_media_lines() {
+        let answer = ANSWER.split("m=video").next().unwrap();
+        let diagnostics = check(answer);
+
+        assert_eq!(
+            messages(&diagnostics),
+            vec!["The answer has 1 media lines, but the offer has 2"]
+        );
+        assert_eq!(diagnostics[0].line_number, None);
+    }
+
+    #[test]
+    fn reports_problems_at_the_answered_media_line() {
+        let answer = ANSWER
+            .replace(
+                "m=video 9 UDP/TLS/RTP/SAVPF 96",
+                "m=audio 9 UDP/TLS/RTP/SAVPF 0 96",
+            )
+            .replace("a=mid:1", "a=mid:2")
+            .replace(
+                "a=recvonly\r\na=setup:active\r\na=rtpmap:96",
+                "a=sendonly\r\na=setup:actpass\r\na=rtpmap:96",
+            );
+        let diagnostics = check(&answer);
+
+        assert_eq!(
+            messages(&diagnostics),
+            vec![
+                "Media line 1 is audio, but video was offered",
+                "Media line 1 has mid Some(\"2\"), but Some(\"1\") was offered",
+                "Codec 0 static in media line 1 was not offered",
+                "Direction sendonly in media line 1 does not answer the offered sendonly",
+                "Media line 1 answers a=setup:actpass",
+            ]
+        );
+        for d in &diagnostics {
+            assert_eq!(d.severity, Severity::Error);
+            assert_eq!(d.media_line, Some(1));
+            assert_eq!(d.line_number, Some(15));
+            assert_eq!(d.line.as_deref(), Some("m=audio 9 UDP/TLS/RTP/SAVPF 0 96"));
+        }
+    }
+
+    #[test]
+    fn rejected_media_lines_are_not_checked() {
+        let answer = ANSWER
+            .replace("m=video 9", "m=video 0")
+            .replace("a=rtpmap:96 VP8/90000", "a=rtpmap:96 H264/90000");
+
+        assert!(check(&answer).is_empty());
+    }
+
+    #[test]
+    fn bundled_media_lines_share_the_transport() {
+        let without_session_credentials = fixture(ANSWER)
+            .replace(
+                "a=ice-ufrag:answ\r\na=ice-pwd:answerpasswordanswer1234\r\n",
+                "",
+            )
+            .replace(
+                "a=mid:0\r\n",
+                "a=mid:0\r\na=ice-ufrag:answ\r\na=ice-pwd:answerpasswordanswer1234\r\n",
+            );
+        assert!(validate(&without_session_credentials, None).is_ok());
+
+        let unbundled =
+            without_session_credentials.replace("a=group:BUNDLE 0 1", "a=group:BUNDLE 0");
+        let report = validate(&unbundled, None).unwrap_err();
+        assert!(!report.valid);
+        assert_eq!(
+            messages(&report.diagnostics),
+            vec![
+                "Missing a=ice-ufrag for media line 1",
+                "Missing a=ice-pwd for media line 1"
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_every_unparseable_line() {
+        let sdp = fixture(ANSWER)
+            .replace(
+                "c=IN IP4 0.0.0.0\r\na=mid:0",
+                "c=IN IP4 0.0.0.0.0\r\na=mid:0",
+            )
+            .replace("a=rtpmap:96 VP8/90000", "a=rtpmap:x VP8/90000");
+        let report = parse(&sdp).unwrap_err();
+
+        let lines = report
+            .diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .filter_map(|d| d.line_number)
+            .collect::<Vec<_>>();
+        assert!(lines.contains(&10), "{}", report);
+        assert!(lines.contains(&20), "{}", report);
+    }
+}
@@ -0,0 +1,185 @@
+// Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
+
+//! Audio level and voice activity of incoming audio. A `level` element in front of the sink
+//! of every incoming audio stream measures its loudness, and the stream is speaking while
+//! it is louder than a threshold. With the `vad` option, webrtcdsp's voice activity
+//! detection decides instead. Both are published as session events.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use gst::prelude::*;
+use gstreamer as gst;
+
+use super::events::EventBus;
+
+const LEVEL_PREFIX: &str = "level-";
+const VAD_PREFIX: &str = "vad-";
+const LEVEL_INTERVAL_NS: u64 = 250_000_000;
+/// the level above which a stream is speaking, in dBov
+const SPEAKING_THRESHOLD_DB: f64 = -50.0;
+/// the quiet intervals in a row after which a stream stops speaking, so that it keeps
+/// speaking between words
+const HANGOVER_INTERVALS: u32 = 3;
+
+/// The launch description of the destination of an incoming audio stream, measuring its
+/// level and, with `vad`, detecting voice where webrtcdsp is available
+pub fn destination_description(stream: &str, vad: bool) -> String {
+    let detection = if vad && gst::ElementFactory::find("webrtcdsp").is_some() {
+        // the echo canceller needs a probe on the send path, which there is none of
+        format!(
+            "webrtcdsp name={}{} echo-cancel=false noise-suppression=false gain-control=false \
+            high-pass-filter=false voice-detection=true ! audioconvert ! ",
+            VAD_PREFIX, stream
+        )
+    } else {
+        if vad {
+            warn!(
+                "No webrtcdsp; detecting speaking on {} by its level",
+                stream
+            );
+        }
+        String::new()
+    };
+
+    format!(
+        "queue ! audioconvert ! audioresample ! {}level name={}{} interval={} ! fakesink",
+        detection, LEVEL_PREFIX, stream, LEVEL_INTERVAL_NS
+    )
+}
+
+/// Tells from the level of an audio stream whether it is speaking
+#[derive(Debug, Default)]
+pub struct SpeechDetector {
+    speaking: bool,
+    quiet: u32,
+}
+
+impl SpeechDetector {
+    /// Updates the detector with the level of the latest interval, in dBov. Returns whether
+    /// the stream is speaking when that changes.
+    pub fn update(&mut self, level: f64) -> Option<bool> {
+        if level > SPEAKING_THRESHOLD_DB {
+            self.quiet = 0;
+            if !self.speaking {
+                self.speaking = true;
+                return Some(true);
+            }
+        } else if self.speaking {
+            self.quiet += 1;
+            if self.quiet >= HANGOVER_INTERVALS {
+                self.speaking = false;
+                self.quiet = 0;
+                return Some(false);
+            }
+        }
+
+        None
+    }
+}
+
+#[derive(Debug, Default)]
+struct Stream {
+    detector: SpeechDetector,
+    /// whether webrtcdsp detects the voice of the stream, instead of its level
+    vad: bool,
+}
+
+/// Publishes the level of every incoming audio stream of the pipeline as the `level` event,
+/// e.g. `src_0 -32.5` in dBov, and whether it is speaking as the `speaking` event, e.g.
+/// `src_0 true`, when that changes. The messages are read from the bus's `sync-message`
+/// signal, which other handlers can connect to as well.
+pub fn watch(pipeline: &gst::Pipeline, events: Arc<EventBus>) {
+    let streams = Mutex::new(HashMap::<String, Stream>::new());
+    let bus = pipeline.get_bus().expect("Pipeline has no bus");
+
+    bus.enable_sync_message_emission();
+    bus.connect_sync_message(move |_bus, message| {
+        if message.get_type() != gst::MessageType::Element {
+            return;
+        }
+        if let (Some(structure), Some(source)) = (message.get_structure(), message.get_src()) {
+            on_message(&streams, &events, &source.get_name(), structure);
+        }
+    });
+}
+
+/// Handles a level or voice activity message, ignoring other messages
+fn on_message(
+    streams: &Mutex<HashMap<String, Stream>>,
+    events: &EventBus,
+    source: &str,
+    structure: &gst::StructureRef,
+) {
+    if structure.get_name() == "level" && source.starts_with(LEVEL_PREFIX) {
+        let stream = &source[LEVEL_PREFIX.len()..];
+        let level = match loudest_channel(structure) {
+            Some(level) => level,
+            None => return,
+        };
+        events.publish("level", &format!("{} {:.1}", stream, level));
+
+        let mut streams = streams.lock().unwrap();
+        let state = streams.entry(stream.to_string()).or_default();
+        if !state.vad {
+            if let Some(speaking) = state.detector.update(level) {
+                publish_speaking(events, stream, speaking);
+            }
+        }
+        return;
+    }
+
+    if structure.get_name() == "voice-activity" && source.starts_with(VAD_PREFIX) {
+        let stream = &source[VAD_PREFIX.len()..];
+        streams
+            .lock()
+            .unwrap()
+            .entry(stream.to_string())
+            .or_default()
+            .vad = true;
+
+        if let Ok(speaking) = structure.get_some::<bool>("stream-has-voice") {
+            publish_speaking(events, stream, speaking);
+        }
+    }
+}
+
+/// The rms level of the loudest channel of a level message
+fn loudest_channel(structure: &gst::StructureRef) -> Option<f64> {
+    let rms = structure.get::<glib::ValueArray>("rms").ok().flatten()?;
+    rms.iter()
+        .filter_map(|v| v.get_some::<f64>().ok())
+        .fold(None, |max, level| Some(level.max(max.unwrap_or(level))))
+}
+
+fn publish_speaking(events: &EventBus, stream: &str, speaking: bool) {
+    info!("Incoming audio {} speaking: {}", stream, speaking);
+    events.publish("speaking", &format!("{} {}", stream, speaking));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speaks_above_the_threshold() {
+        let mut detector = SpeechDetector::default();
+
+        assert_eq!(detector.update(-70.0), None);
+        assert_eq!(detector.update(-30.0), Some(true));
+        assert_eq!(detector.update(-20.0), None);
+    }
+
+    #[test]
+    fn keeps_speaking_between_words() {
+        let mut detector = SpeechDetector::default();
+        detector.update(-30.0);
+
+        assert_eq!(detector.update(-70.0), None);
+        assert_eq!(detector.update(-70.0), None);
+        assert_eq!(detector.update(-30.0), None);
+        assert_eq!(detector.update(-70.0), None);
+        assert_eq!(detector.update(-70.0), None);
+        assert_eq!(detector.update(-70.0), Some(false));
+    }
+}
@@ -0,0 +1,701 @@
+// Copyright (C) 2019-2020 Motorola Solutions, Inc. All rights reserved.
+
+//! Two sessions in one process, negotiating with each other by exchanging descriptions and
+//! candidates in memory instead of over http. Needs the gstreamer plugins the server uses.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gst::prelude::*;
+use gstreamer as gst;
+use gstreamer_webrtc as gst_webrtc;
+
+use futures::channel::mpsc::UnboundedReceiver;
+
+use actix_web::{web, App, HttpServer};
+
+use webrtcbin_playground::client::ClientOptions;
+use webrtcbin_playground::domain::*;
+use webrtcbin_playground::events::{EventBus, SessionEvent};
+use webrtcbin_playground::extmap::HeaderExtension;
+use webrtcbin_playground::impairment::{Impairment, MediaPath, SessionImpairments};
+use webrtcbin_playground::snapshot::{self, ImageFormat};
+use webrtcbin_playground::{api, client, inspect, keyframe, validation};
+
+const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(5);
+const CANDIDATE_TIMEOUT: Duration = Duration::from_millis(500);
+const MEDIA_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_BUFFERS: u64 = 10;
+const SKIP_VARIABLE: &str = "SKIP_LOOPBACK_TESTS";
+
+type PipelineConstructor =
+    fn(
+        Arc<EventBus>,
+        Arc<SessionImpairments>,
+        &SessionOptions,
+    ) -> Result<(gst::Pipeline, gst::Element, Receiver<IceCandidate>), String>;
+
+/// One side of the loopback, counting the buffers that reach its sinks
+struct LoopbackPeer {
+    name: &'static str,
+    pipeline: gst::Pipeline,
+    webrtcbin: gst::Element,
+    local_candidates: Receiver<IceCandidate>,
+    options: SessionOptions,
+    events: Arc<EventBus>,
+    impairments: Arc<SessionImpairments>,
+    buffers: Arc<AtomicU64>,
+}
+
+impl LoopbackPeer {
+    fn offering(options: SessionOptions) -> Self {
+        LoopbackPeer::new("offerer", create_send_receive_pipeline, options)
+    }
+
+    fn answering(options: SessionOptions) -> Self {
+        LoopbackPeer::new("answerer", create_answering_pipeline, options)
+    }
+
+    fn new(name: &'static str, create: PipelineConstructor, options: SessionOptions) -> Self {
+        let events = Arc::new(EventBus::new());
+        let impairments = Arc::new(SessionImpairments::new());
+        let (pipeline, webrtcbin, local_candidates) =
+            create(events.clone(), impairments.clone(), &options)
+                .expect("Could not create pipeline");
+
+        let buffers = Arc::new(AtomicU64::new(0));
+        count_sink_buffers(&pipeline, buffers.clone());
+
+        LoopbackPeer {
+            name,
+            pipeline,
+            webrtcbin,
+            local_candidates,
+            options,
+            events,
+            impairments,
+            buffers,
+        }
+    }
+
+    fn buffers(&self) -> u64 {
+        self.buffers.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for LoopbackPeer {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
+
+/// Counts the buffers reaching the fakesinks that incoming streams end in
+fn count_sink_buffers(pipeline: &gst::Pipeline, buffers: Arc<AtomicU64>) {
+    pipeline
+        .connect("deep-element-added", false, move |values| {
+            let element = values[2]
+                .get::<gst::Element>()
+                .expect("Invalid argument")
+                .expect("Should never be null.");
+            let is_sink = matches!(
+                element.get_factory(),
+                Some(f) if f.get_name().as_str() == "fakesink"
+            );
+
+            if let (true, Some(pad)) = (is_sink, element.get_static_pad("sink")) {
+                let buffers = buffers.clone();
+                pad.add_probe(gst::PadProbeType::BUFFER, move |_pad, _info| {
+                    buffers.fetch_add(1, Ordering::Relaxed);
+                    gst::PadProbeReturn::Ok
+                });
+            }
+
+            None
+        })
+        .unwrap();
+}
+
+/// Initializes gstreamer. A missing webrtcbin fails the test, unless the tests were
+/// explicitly skipped by setting `SKIP_VARIABLE`.
+fn init() -> bool {
+    gst::init().expect("Could not initialize gstreamer.");
+
+    if std::env::var_os(SKIP_VARIABLE).is_some() {
+        eprintln!("Skipping loopback test; {} is set", SKIP_VARIABLE);
+        return false;
+    }
+
+    assert!(
+        gst::ElementFactory::find("webrtcbin").is_some(),
+        "webrtcbin is not available; install gst-plugins-bad, or set {} to skip the loopback tests",
+        SKIP_VARIABLE
+    );
+    true
+}
+
+/// Negotiates without trickle: the candidates of both parties are part of the descriptions
+fn negotiate_in_sdp(offerer: &LoopbackPeer, answerer: &LoopbackPeer) {
+    let offer = get_offer(
+        &offerer.webrtcbin,
+        &offerer.options,
+        &offerer.local_candidates,
+    )
+    .expect("Could not get offer");
+    assert!(
+        offer.contains("a=candidate"),
+        "No candidates in offer:\n{}",
+        offer
+    );
+
+    let answer = process_sdp_offer(
+        &answerer.webrtcbin,
+        &answerer.options,
+        offer,
+        &answerer.local_candidates,
+    )
+    .expect("Could not answer offer");
+    assert!(
+        answer.contains("a=candidate"),
+        "No candidates in answer:\n{}",
+        answer
+    );
+
+    process_sdp_answer(&offerer.webrtcbin, &offerer.options, answer)
+        .expect("Could not apply answer");
+}
+
+/// Negotiates with descriptions without candidates, then trickles the candidates
+fn negotiate_with_trickle(offerer: &LoopbackPeer, answerer: &LoopbackPeer) {
+    let offer = local_description(&offerer.webrtcbin);
+
+    let answer = answer_sdp_offer(
+        &answerer.webrtcbin,
+        &answerer.options,
+        offer,
+        &answerer.local_candidates,
+    )
+    .expect("Could not answer offer");
+
+    process_sdp_answer(&offerer.webrtcbin, &offerer.options, answer)
+        .expect("Could not apply answer");
+
+    trickle(offerer, answerer);
+    trickle(answerer, offerer);
+}
+
+/// Hands the local candidates of one party to the other, up to the end of candidates of
+/// every media line
+fn trickle(from: &LoopbackPeer, to: &LoopbackPeer) {
+    while let Ok(candidate) = from.local_candidates.recv_timeout(CANDIDATE_TIMEOUT) {
+        let end = candidate.is_end_of_candidates();
+        let mut candidates = vec![candidate];
+        if end {
+            candidates.extend(take_end_of_candidates(&from.local_candidates));
+        }
+
+        for candidate in candidates {
+            add_remote_candidate(
+                &to.webrtcbin,
+                &to.options,
+                candidate.get_media_line_index(),
+                &candidate.get_candidate_str(),
+            )
+            .expect("Could not add remote candidate");
+        }
+
+        if end {
+            break;
+        }
+    }
+}
+
+/// The offer webrtcbin created on its own, as the local description
+fn local_description(webrtcbin: &gst::Element) -> String {
+    let deadline = Instant::now() + NEGOTIATION_TIMEOUT;
+
+    loop {
+        let description = webrtcbin
+            .get_property("local-description")
+            .unwrap()
+            .get::<gst_webrtc::WebRTCSessionDescription>()
+            .unwrap();
+
+        if let Some(d) = description {
+            return d.get_sdp().as_text().unwrap();
+        }
+
+        assert!(
+            Instant::now() < deadline,
+            "Webrtcbin did not create an offer"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Waits until every peer's sinks received some buffers, and fails the test otherwise
+fn assert_media_flows(peers: &[&LoopbackPeer]) {
+    let deadline = Instant::now() + MEDIA_TIMEOUT;
+
+    while peers.iter().any(|p| p.buffers() < MIN_BUFFERS) {
+        if Instant::now() > deadline {
+            let received = peers
+                .iter()
+                .map(|p| format!("{}: {}", p.name, p.buffers()))
+                .collect::<Vec<_>>();
+            panic!(
+                "Expected {} buffers within {:?}, received {}",
+                MIN_BUFFERS,
+                MEDIA_TIMEOUT,
+                received.join(", ")
+            );
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Waits until the peer publishes the event, and fails the test otherwise
+fn assert_event_published(
+    peer: &LoopbackPeer,
+    events: &mut UnboundedReceiver<SessionEvent>,
+    event: &str,
+) {
+    let deadline = Instant::now() + MEDIA_TIMEOUT;
+
+    loop {
+        while let Ok(e) = events.try_recv() {
+            if e.event == event {
+                return;
+            }
+        }
+
+        assert!(
+            Instant::now() < deadline,
+            "{} did not publish {} within {:?}",
+            peer.name,
+            event,
+            MEDIA_TIMEOUT
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn media_flows_with_trickled_candidates() {
+    if !init() {
+        return;
+    }
+
+    let offerer = LoopbackPeer::offering(SessionOptions::default());
+    let answerer = LoopbackPeer::answering(SessionOptions::default());
+
+    negotiate_with_trickle(&offerer, &answerer);
+    assert_media_flows(&[&offerer, &answerer]);
+}
+
+#[test]
+fn media_flows_with_candidates_in_sdp() {
+    if !init() {
+        return;
+    }
+
+    let offerer = LoopbackPeer::offering(SessionOptions::default());
+    let answerer = LoopbackPeer::answering(SessionOptions::default());
+
+    negotiate_in_sdp(&offerer, &answerer);
+    assert_media_flows(&[&offerer, &answerer]);
+}
+
+#[test]
+fn recvonly_offerer_receives_media() {
+    if !init() {
+        return;
+    }
+
+    let offerer = LoopbackPeer::offering(SessionOptions {
+        direction: MediaDirection::RecvOnly,
+        ..SessionOptions::default()
+    });
+    let answerer = LoopbackPeer::answering(SessionOptions::default());
+
+    negotiate_in_sdp(&offerer, &answerer);
+    assert_media_flows(&[&offerer]);
+}
+
+#[test]
+fn media_flows_without_bundle() {
+    if !init() {
+        return;
+    }
+
+    let options = SessionOptions {
+        bundle_policy: BundlePolicy::None,
+        ..SessionOptions::default()
+    };
+    let offerer = LoopbackPeer::offering(options.clone());
+    let answerer = LoopbackPeer::answering(options);
+
+    negotiate_in_sdp(&offerer, &answerer);
+    assert_media_flows(&[&offerer, &answerer]);
+}
+
+/// The factory of the element on the other side of a netsim element's pad
+fn netsim_neighbour(peer: &LoopbackPeer, netsim: &str, pad: &str) -> Option<String> {
+    let netsim = peer.pipeline.get_by_name(netsim)?;
+    let neighbour = netsim
+        .get_static_pad(pad)?
+        .get_peer()?
+        .get_parent_element()?;
+    Some(neighbour.get_factory()?.get_name().to_string())
+}
+
+/// The retransmissions requested by the jitterbuffers of a peer
+fn retransmission_requests(peer: &LoopbackPeer) -> u64 {
+    peer.pipeline
+        .iterate_recurse()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| {
+            e.get_factory()
+                .is_some_and(|f| f.get_name() == "rtpjitterbuffer")
+        })
+        .filter_map(|e| e.get_property("stats").ok())
+        .filter_map(|v| v.get::<gst::Structure>().ok().flatten())
+        .filter_map(|s| s.get_some::<u64>("rtx-count").ok())
+        .sum()
+}
+
+#[test]
+fn transport_loss_is_retransmitted() {
+    if !init() {
+        return;
+    }
+
+    let options = SessionOptions {
+        nack: true,
+        ..SessionOptions::default()
+    };
+    let offerer = LoopbackPeer::offering(options.clone());
+    let answerer = LoopbackPeer::answering(options);
+    let loss = Impairment {
+        loss: 0.05,
+        ..Impairment::default()
+    };
+    offerer
+        .impairments
+        .set(&offerer.pipeline, MediaPath::Send, loss.clone());
+    answerer
+        .impairments
+        .set(&answerer.pipeline, MediaPath::Receive, loss);
+
+    negotiate_with_trickle(&offerer, &answerer);
+    assert_media_flows(&[&offerer, &answerer]);
+
+    // the loss happens between rtpbin and the transports, like on the network
+    assert_eq!(
+        netsim_neighbour(&offerer, "impair-send-transport-0", "sink").as_deref(),
+        Some("rtpbin")
+    );
+    assert_eq!(
+        netsim_neighbour(&answerer, "impair-receive-transport-0", "src").as_deref(),
+        Some("rtpbin")
+    );
+
+    // so the answerer's jitterbuffers notice the missing packets and ask for them again
+    let deadline = Instant::now() + MEDIA_TIMEOUT;
+    while retransmission_requests(&answerer) == 0 {
+        assert!(
+            Instant::now() < deadline,
+            "No retransmissions requested within {:?}",
+            MEDIA_TIMEOUT
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// The headless client negotiating with the server's api, both running in this process
+#[actix_rt::test]
+async fn headless_client_receives_media_from_the_server() {
+    if !init() {
+        return;
+    }
+
+    let state = web::Data::new(api::AppState::new());
+    let server = HttpServer::new(move || App::new().app_data(state.clone()).configure(api::routes))
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .expect("Could not bind the server");
+    let address = server.addrs()[0];
+    let server = server.run();
+
+    let options = ClientOptions {
+        server: format!("http://{}", address),
+        timeout: MEDIA_TIMEOUT,
+        min_buffers: MIN_BUFFERS,
+        ..ClientOptions::default()
+    };
+    let received = client::run(&options).await;
+    server.stop(true).await;
+
+    let received = received.expect("The client did not receive the server's media");
+    assert!(
+        received.iter().any(|(kind, _)| kind == "video"),
+        "{:?}",
+        received
+    );
+}
+
+/// The codecs of the offer's media lines of the given kind
+fn offered_codecs(media: &[inspect::MediaSummary], kind: &str) -> Vec<inspect::CodecSummary> {
+    media
+        .iter()
+        .filter(|m| m.kind == kind)
+        .flat_map(|m| m.codecs.clone())
+        .collect()
+}
+
+/// The media lines of a new offer of the peer
+fn offered_media(offerer: &LoopbackPeer) -> Vec<inspect::MediaSummary> {
+    let offer = get_offer(
+        &offerer.webrtcbin,
+        &offerer.options,
+        &offerer.local_candidates,
+    )
+    .expect("Could not get offer");
+    let session = validation::parse(&offer).expect("Could not parse offer");
+
+    inspect::summarize(&session).media
+}
+
+fn has_codec(codecs: &[inspect::CodecSummary], name: &str) -> bool {
+    codecs.iter().any(|c| c.name.eq_ignore_ascii_case(name))
+}
+
+#[test]
+fn offer_has_no_resilience_by_default() {
+    if !init() {
+        return;
+    }
+
+    let offerer = LoopbackPeer::offering(SessionOptions::default());
+    let video = offered_codecs(&offered_media(&offerer), "video");
+
+    assert!(!has_codec(&video, "rtx"), "Unexpected rtx in {:?}", video);
+    assert!(!has_codec(&video, "red"), "Unexpected red in {:?}", video);
+    assert!(
+        !has_codec(&video, "ulpfec"),
+        "Unexpected ulpfec in {:?}",
+        video
+    );
+}
+
+#[test]
+fn offer_has_nack_and_fec() {
+    if !init() {
+        return;
+    }
+
+    let offerer = LoopbackPeer::offering(SessionOptions {
+        nack: true,
+        fec: FecType::UlpRed,
+        fec_percentage: 20,
+        audio_red: true,
+        ..SessionOptions::default()
+    });
+    let media = offered_media(&offerer);
+    let video = offered_codecs(&media, "video");
+    let audio = offered_codecs(&media, "audio");
+
+    let vp8 = video
+        .iter()
+        .find(|c| c.name.eq_ignore_ascii_case("vp8"))
+        .expect("No vp8 in offer");
+    assert!(
+        vp8.rtcp_fb.iter().any(|f| f.ends_with(" nack")),
+        "No nack feedback for vp8: {:?}",
+        vp8.rtcp_fb
+    );
+    assert!(has_codec(&video, "rtx"), "No rtx in {:?}", video);
+    assert!(has_codec(&video, "red"), "No red in {:?}", video);
+    assert!(has_codec(&video, "ulpfec"), "No ulpfec in {:?}", video);
+    assert!(has_codec(&audio, "red"), "No red in {:?}", audio);
+}
+
+#[test]
+fn offer_negotiates_twcc_with_congestion_control() {
+    if !init() {
+        return;
+    }
+
+    let twcc = "http://www.ietf.org/id/draft-holmer-rmcat-transport-wide-cc-extensions-01";
+    for direction in &[MediaDirection::SendRecv, MediaDirection::RecvOnly] {
+        let offerer = LoopbackPeer::offering(SessionOptions {
+            direction: *direction,
+            congestion_control: true,
+            ..SessionOptions::default()
+        });
+
+        for media in offered_media(&offerer) {
+            assert!(
+                media.extmaps.iter().any(|e| e.contains(twcc)),
+                "No twcc extmap for {} in {:?} offer: {:?}",
+                media.kind,
+                direction,
+                media.extmaps
+            );
+        }
+    }
+}
+
+#[test]
+fn offer_has_keyframe_feedback() {
+    if !init() {
+        return;
+    }
+
+    let offerer = LoopbackPeer::offering(SessionOptions::default());
+    let video = offered_codecs(&offered_media(&offerer), "video");
+    let vp8 = video
+        .iter()
+        .find(|c| c.name.eq_ignore_ascii_case("vp8"))
+        .expect("No vp8 in offer");
+
+    for feedback in &[" nack pli", " ccm fir"] {
+        assert!(
+            vp8.rtcp_fb.iter().any(|f| f.ends_with(feedback)),
+            "No{} feedback for vp8: {:?}",
+            feedback,
+            vp8.rtcp_fb
+        );
+    }
+}
+
+#[test]
+fn keyframe_request_reaches_the_remote_encoder() {
+    if !init() {
+        return;
+    }
+
+    let offerer = LoopbackPeer::offering(SessionOptions::default());
+    let answerer = LoopbackPeer::answering(SessionOptions::default());
+
+    negotiate_in_sdp(&offerer, &answerer);
+    assert_media_flows(&[&offerer, &answerer]);
+
+    // keyframe requests are announced, not replayed, so subscribe before requesting one
+    let mut events = offerer.events.subscribe();
+    assert!(
+        keyframe::request_key_unit(&answerer.webrtcbin) > 0,
+        "No incoming video on the answerer"
+    );
+    assert_event_published(&offerer, &mut events, "keyframe-requested");
+}
+
+#[test]
+fn offer_has_simulcast_layers() {
+    if !init() {
+        return;
+    }
+
+    let offerer = LoopbackPeer::offering(SessionOptions {
+        direction: MediaDirection::RecvOnly,
+        simulcast: vec!["h".to_string(), "l".to_string()],
+        ..SessionOptions::default()
+    });
+    let offer = get_offer(
+        &offerer.webrtcbin,
+        &offerer.options,
+        &offerer.local_candidates,
+    )
+    .expect("Could not get offer");
+
+    for line in &["a=simulcast:recv h;~l", "a=rid:h recv", "a=rid:l recv"] {
+        assert!(offer.contains(line), "No {} in offer:\n{}", line, offer);
+    }
+    validation::parse(&offer).expect("Invalid offer");
+}
+
+#[test]
+fn offer_has_header_extensions() {
+    if !init() {
+        return;
+    }
+
+    let expected = [
+        ("audio", "urn:ietf:params:rtp-hdrext:ssrc-audio-level"),
+        ("video", "urn:3gpp:video-orientation"),
+        ("audio", "urn:ietf:params:rtp-hdrext:sdes:mid"),
+        (
+            "video",
+            "http://www.webrtc.org/experiments/rtp-hdrext/abs-send-time",
+        ),
+    ];
+    for direction in &[MediaDirection::SendRecv, MediaDirection::RecvOnly] {
+        let offerer = LoopbackPeer::offering(SessionOptions {
+            direction: *direction,
+            header_extensions: vec![
+                HeaderExtension::Mid,
+                HeaderExtension::AbsSendTime,
+                HeaderExtension::AudioLevel,
+                HeaderExtension::VideoOrientation,
+            ],
+            ..SessionOptions::default()
+        });
+        let media = offered_media(&offerer);
+
+        for (kind, uri) in &expected {
+            let extmaps = &media
+                .iter()
+                .find(|m| m.kind == *kind)
+                .expect("No media line of the kind")
+                .extmaps;
+            assert!(
+                extmaps.iter().any(|e| e.contains(uri)),
+                "No {} for {} in {:?} offer: {:?}",
+                uri,
+                kind,
+                direction,
+                extmaps
+            );
+        }
+    }
+}
+
+#[test]
+fn incoming_audio_publishes_level_and_speaking() {
+    if !init() {
+        return;
+    }
+
+    let offerer = LoopbackPeer::offering(SessionOptions::default());
+    let answerer = LoopbackPeer::answering(SessionOptions::default());
+
+    negotiate_in_sdp(&offerer, &answerer);
+    assert_media_flows(&[&offerer, &answerer]);
+
+    // the test source is a loud sine
+    let mut events = answerer.events.subscribe();
+    assert_event_published(&answerer, &mut events, "level");
+    assert_event_published(&answerer, &mut events, "speaking");
+}
+
+#[test]
+fn snapshot_of_incoming_video() {
+    if !init() {
+        return;
+    }
+
+    let offerer = LoopbackPeer::offering(SessionOptions::default());
+    let answerer = LoopbackPeer::answering(SessionOptions::default());
+
+    negotiate_in_sdp(&offerer, &answerer);
+    assert_media_flows(&[&offerer, &answerer]);
+
+    let frame = snapshot::latest_frame(&answerer.pipeline, None).expect("No decoded video");
+    let png = snapshot::encode(&frame, ImageFormat::Png).expect("Could not encode png");
+    assert!(png.starts_with(b"\x89PNG"), "Not a png");
+    let jpeg = snapshot::encode(&frame, ImageFormat::Jpeg).expect("Could not encode jpeg");
+    assert!(jpeg.starts_with(&[0xff, 0xd8]), "Not a jpeg");
+}